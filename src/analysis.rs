@@ -0,0 +1,361 @@
+use crate::parser::{ScopeImpl, ScopeImplType, Statement, Expression, Condition};
+use crate::diagnostic::{self, Diagnostic};
+use crate::span::Span;
+use crate::register;
+use crate::tokenizer::Type;
+use std::collections::{BTreeSet, HashMap};
+use std::str::from_utf8;
+
+// what `syscall` leaves changed, its result in rax, and rcx and r11 which the cpu overwrites
+const SYSCALL: [&str; 3] = ["rax", "rcx", "r11"];
+
+// register families
+type Registers = BTreeSet<&'static str>;
+
+// registers a call may have overwritten that haven't been written since, with a label for the call that did
+type Clobbered = HashMap<&'static str, (Span, String)>;
+
+// what a function does to registers by itself, before the functions it calls are accounted for
+struct Summary {
+    name: Vec<u8>,
+    writes: Registers,
+    calls: Vec<Vec<u8>>,
+    // saved with `uses` or promised with `preserves`, so the caller gets them back unchanged
+    restored: Registers,
+}
+
+struct Analysis {
+    // registers every function can change for whoever calls it
+    clobbers: HashMap<Vec<u8>, Registers>,
+    // reads that were already warned about, loops are walked twice
+    warned: Vec<Span>,
+    warnings: Vec<Diagnostic>,
+}
+
+// warns wherever a register is read after a call that can overwrite it. these are only warnings,
+// the walk can't tell which way branches go or what inline assembly other than `#syscall` does
+pub fn check(scopes: &[ScopeImpl]) -> Vec<Diagnostic> {
+    let functions = functions(scopes);
+    let mut analysis = Analysis { clobbers: clobbers(&functions), warned: vec![], warnings: vec![] };
+    for function in functions {
+        analysis.scope(function, Clobbered::new());
+    }
+    analysis.warnings
+}
+
+fn functions(scopes: &[ScopeImpl]) -> Vec<&ScopeImpl> {
+    scopes
+        .iter()
+        .flat_map(|scope| &scope.scope)
+        .filter_map(|statement| match statement {
+            Statement::Scoped { scoped } if matches!(scoped.scope_type, ScopeImplType::Fn { .. }) => Some(scoped),
+            _ => None,
+        })
+        .collect()
+}
+
+// calls can go in circles, so what callees clobber is added until nothing new turns up
+fn clobbers(functions: &[&ScopeImpl]) -> HashMap<Vec<u8>, Registers> {
+    let summaries: Vec<Summary> = functions.iter().filter_map(|function| summarize(function)).collect();
+    let mut clobbers: HashMap<Vec<u8>, Registers> = summaries.iter().map(|s| (s.name.clone(), &s.writes - &s.restored)).collect();
+    loop {
+        let mut changed = false;
+        for summary in &summaries {
+            let mut writes = clobbers[&summary.name].clone();
+            for f in &summary.calls {
+                match clobbers.get(f) {
+                    Some(callee) => writes.extend(callee),
+                    // defined somewhere else, so it can do anything the ABI lets it
+                    None => writes.extend(&register::CALLER_SAVED),
+                }
+            }
+            writes.retain(|r| !summary.restored.contains(r));
+            if writes.len() > clobbers[&summary.name].len() {
+                clobbers.insert(summary.name.clone(), writes);
+                changed = true;
+            }
+        }
+        if !changed {
+            return clobbers;
+        }
+    }
+}
+
+fn summarize(function: &ScopeImpl) -> Option<Summary> {
+    let (name, scratch, uses, preserves) = match &function.scope_type {
+        ScopeImplType::Fn { name, scratch, uses, preserves, .. } => (name, scratch, uses, preserves),
+        _ => return None,
+    };
+    // scratch registers are overwritten by expressions without being saved
+    let mut writes: Registers = scratch.iter().map(|r| register::family(r)).collect();
+    let mut calls = vec![];
+    function.each_statement(&mut |statement| match statement {
+        Statement::RegisterAssign { register, .. } => {
+            writes.insert(register::family(register));
+        }
+        Statement::Return { expression: Some(_), .. } => {
+            writes.insert("rax");
+        }
+        Statement::Call { f, arguments, .. } => {
+            writes.extend(&register::ARGUMENTS[..arguments.len()]);
+            calls.push(f.clone());
+        }
+        Statement::InlineAssembly { instructions, .. } if is_syscall(instructions) => writes.extend(&SYSCALL),
+        _ => {}
+    });
+    Some(Summary {
+        name: name.clone(),
+        writes,
+        calls,
+        restored: uses.iter().chain(preserves).map(|r| register::family(r)).collect(),
+    })
+}
+
+// `#syscall`, with or without a comment after it
+fn is_syscall(instructions: &[u8]) -> bool {
+    let instruction = instructions.split(|c| *c == b';').next().unwrap();
+    from_utf8(instruction).is_ok_and(|i| i.trim() == "syscall")
+}
+
+// a register is clobbered after two paths join if it is on either of them
+fn merge(mut left: Clobbered, right: Clobbered) -> Clobbered {
+    for (r, call) in right {
+        left.entry(r).or_insert(call);
+    }
+    left
+}
+
+impl Analysis {
+    fn scope(&mut self, scope: &ScopeImpl, mut clobbered: Clobbered) -> Clobbered {
+        for statement in &scope.scope {
+            clobbered = self.statement(statement, clobbered);
+        }
+        clobbered
+    }
+
+    fn scoped(&mut self, scoped: &ScopeImpl, mut clobbered: Clobbered) -> Clobbered {
+        match &scoped.scope_type {
+            ScopeImplType::If { condition, else_scope } => {
+                self.condition(condition, &mut clobbered);
+                let taken = self.scope(scoped, clobbered.clone());
+                let skipped = match else_scope {
+                    Some(else_scope) => self.scoped(else_scope, clobbered),
+                    None => clobbered,
+                };
+                merge(taken, skipped)
+            }
+            ScopeImplType::While { condition } => self.repeat(scoped, Some(condition), clobbered),
+            ScopeImplType::Loop => self.repeat(scoped, None, clobbered),
+            _ => self.scope(scoped, clobbered),
+        }
+    }
+
+    // a loop's body can start with what its last pass left behind, so it's walked a second time from there
+    fn repeat(&mut self, body: &ScopeImpl, condition: Option<&Condition>, mut clobbered: Clobbered) -> Clobbered {
+        for _ in 0..2 {
+            if let Some(condition) = condition {
+                self.condition(condition, &mut clobbered);
+            }
+            let after = self.scope(body, clobbered.clone());
+            clobbered = merge(clobbered, after);
+        }
+        clobbered
+    }
+
+    fn statement(&mut self, statement: &Statement, mut clobbered: Clobbered) -> Clobbered {
+        match statement {
+            Statement::RegisterAssign { register, expression, .. } => {
+                self.expression(expression, &mut clobbered);
+                // a byte or word write leaves the rest of the register as the call left it
+                if register::width(register) >= Type::Uint32 {
+                    clobbered.remove(register::family(register));
+                }
+            }
+            Statement::RegisterDerefAssign { address, expression, span, .. } => {
+                for r in address.registers() {
+                    self.read(r, *span, &mut clobbered);
+                }
+                self.expression(expression, &mut clobbered);
+            }
+            Statement::Return { expression: Some(expression), .. } => self.expression(expression, &mut clobbered),
+            Statement::Call { f, arguments, span } => {
+                for argument in arguments {
+                    self.expression(argument, &mut clobbered);
+                }
+                for r in &register::ARGUMENTS[..arguments.len()] {
+                    clobbered.remove(r);
+                }
+                let name = from_utf8(f).unwrap();
+                let (writes, external) = match self.clobbers.get(f) {
+                    Some(writes) => (writes.clone(), false),
+                    None => (register::CALLER_SAVED.iter().copied().collect(), true),
+                };
+                // rax is what the call returns, reading it is the point
+                for r in writes.into_iter().filter(|r| *r != "rax") {
+                    let label = if external {
+                        format!("`{}` isn't defined here, so it can overwrite any caller saved register", name)
+                    } else {
+                        format!("`{}` can overwrite `{}`", name, r)
+                    };
+                    clobbered.insert(r, (*span, label));
+                }
+            }
+            Statement::InlineAssembly { instructions, span } if is_syscall(instructions) => {
+                for r in SYSCALL.iter().filter(|r| **r != "rax") {
+                    clobbered.insert(r, (*span, format!("`syscall` overwrites `{}`", r)));
+                }
+            }
+            Statement::Scoped { scoped } => clobbered = self.scoped(scoped, clobbered),
+            _ => {}
+        }
+        clobbered
+    }
+
+    fn condition(&mut self, condition: &Condition, clobbered: &mut Clobbered) {
+        match condition {
+            Condition::Compare { left, right, .. } => {
+                self.expression(left, clobbered);
+                self.expression(right, clobbered);
+            }
+            Condition::And { left, right, .. } | Condition::Or { left, right, .. } => {
+                self.condition(left, clobbered);
+                self.condition(right, clobbered);
+            }
+            Condition::Not { operand, .. } => self.condition(operand, clobbered),
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression, clobbered: &mut Clobbered) {
+        match expression {
+            Expression::Binary { left, right, .. } => {
+                self.expression(left, clobbered);
+                self.expression(right, clobbered);
+            }
+            Expression::Unary { operand, .. } => self.expression(operand, clobbered),
+            Expression::Register { reg, span } => self.read(reg, *span, clobbered),
+            Expression::Load { address, span, .. } => {
+                for r in address.registers() {
+                    self.read(r, *span, clobbered);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // warns once for a register read while a call may have overwritten it
+    fn read(&mut self, reg: &[u8], span: Span, clobbered: &mut Clobbered) {
+        let (call, label) = match clobbered.remove(register::family(reg)) {
+            Some(clobber) => clobber,
+            None => return,
+        };
+        if self.warned.contains(&span) {
+            return;
+        }
+        self.warned.push(span);
+        let name = from_utf8(reg).unwrap();
+        self.warnings.push(
+            Diagnostic::warning(diagnostic::READ_AFTER_CLOBBER, format!("`{}` is read after a call that can overwrite it", name))
+                .with_primary(span, format!("reads `{}`", name))
+                .with_secondary(call, label)
+                .with_note(format!("set `{}` again after the call, or keep the value in a register the call leaves alone", name)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::stream::IStream;
+    use crate::tokenizer::Tokenizer;
+
+    fn parse(source: &str) -> Vec<ScopeImpl> {
+        let text = source.as_bytes().to_vec();
+        let mut is = IStream::new(&text, 0);
+        let tokenizer = Tokenizer::new(&mut is);
+        Parser::new(tokenizer.get_tokens()).unwrap().scope_stack
+    }
+
+    // what every function can overwrite, as `name: rcx rdx`
+    fn clobbered(source: &str) -> Vec<String> {
+        let scopes = parse(source);
+        let clobbers = clobbers(&functions(&scopes));
+        let mut clobbered: Vec<String> = clobbers
+            .iter()
+            .map(|(name, registers)| format!("{}: {}", from_utf8(name).unwrap(), registers.iter().copied().collect::<Vec<_>>().join(" ")))
+            .collect();
+        clobbered.sort();
+        clobbered
+    }
+
+    // the reads that were warned about, as they're written in `source`
+    fn warnings(source: &str) -> Vec<String> {
+        check(&parse(source))
+            .iter()
+            .map(|d| {
+                let span = d.primary_span().unwrap();
+                source[span.start..span.end].to_string()
+            })
+            .collect()
+    }
+
+    // writes rcx and rdx
+    const P: &str = "fn p {\n$rcx = 1\n$rdx = 2\n}\n";
+
+    #[test]
+    fn callees_are_followed_through_cycles() {
+        let source = "fn a {\n$r8 = 1\ncall(b)\n}\nfn b {\n$r9 = 1\ncall(a)\n}\nfn c {\ncall(a)\n}\nfn d {\ncall(d, 1)\n}";
+        assert_eq!(clobbered(source), ["a: r8 r9", "b: r8 r9", "c: r8 r9", "d: rdi"]);
+    }
+
+    #[test]
+    fn functions_defined_elsewhere_clobber_every_caller_saved_register() {
+        assert_eq!(clobbered("fn a {\ncall(puts)\n}"), ["a: r10 r11 r8 r9 rax rcx rdi rdx rsi"]);
+        assert_eq!(warnings("fn a {\n$r10 = 1\ncall(puts)\n$rax = $r10\n}"), ["$r10"]);
+    }
+
+    #[test]
+    fn syscall_clobbers_rcx_and_r11() {
+        assert!(is_syscall(b"syscall"));
+        assert!(is_syscall(b"  syscall ; exit"));
+        assert!(!is_syscall(b"syscalls"));
+        assert!(!is_syscall(b"mov rax, 60"));
+        assert_eq!(clobbered("fn a {\n#syscall ; write\n}"), ["a: r11 rax rcx"]);
+        assert_eq!(warnings("fn a {\n#syscall ; write\n$rdx = $rcx + $r11\n}"), ["$rcx", "$r11"]);
+    }
+
+    #[test]
+    fn saved_and_preserved_registers_are_not_clobbered() {
+        let source = "fn p uses(rbx) preserves(rcx) {\n$rbx = 1\n$rcx = 1\n$rdx = 1\n}\nfn f {\ncall(p)\n$rax = $rbx + $rcx + $rdx\n}";
+        assert_eq!(clobbered(source), ["f: rax rdx", "p: rdx"]);
+        assert_eq!(warnings(source), ["$rdx"]);
+    }
+
+    #[test]
+    fn rax_is_what_a_call_returns() {
+        assert!(warnings("fn p {\nreturn 1\n}\nfn f {\ncall(p)\n$rdx = $rax\n}").is_empty());
+    }
+
+    #[test]
+    fn branches_merge() {
+        let one_side = format!("{}fn f {{\nif $rax == 0 {{\ncall(p)\n}} else {{\n$rsi = 1\n}}\n$rsi = $rcx\n}}", P);
+        assert_eq!(warnings(&one_side), ["$rcx"]);
+        let rewritten = format!("{}fn f {{\nif $rax == 0 {{\ncall(p)\n$rcx = 2\n}}\n$rsi = $rcx\n}}", P);
+        assert!(warnings(&rewritten).is_empty());
+    }
+
+    #[test]
+    fn loops_are_walked_twice_but_warn_once() {
+        // only the second pass reads rcx after the call
+        let source = format!("{}fn f {{\n$rcx = 1\nloop {{\n$rsi = $rcx\n$rdi = $rcx\ncall(p)\n}}\n}}", P);
+        assert_eq!(warnings(&source), ["$rcx"]);
+        let condition = format!("{}fn f {{\nwhile $rdx != 0 {{\ncall(p)\n}}\n}}", P);
+        assert_eq!(warnings(&condition), ["$rdx"]);
+    }
+
+    #[test]
+    fn narrow_writes_dont_undo_a_clobber() {
+        assert_eq!(warnings(&format!("{}fn f {{\ncall(p)\n$cl = 1\n$rsi = $rcx\n}}", P)), ["$rcx"]);
+        assert!(warnings(&format!("{}fn f {{\ncall(p)\n$ecx = 1\n$rsi = $rcx\n}}", P)).is_empty());
+    }
+}
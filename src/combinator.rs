@@ -1,17 +1,11 @@
 use crate::stream::IStream;
 
 pub fn is_ws(c: u8, _is: Option<&mut IStream>) -> bool {
-    match c {
-        b' ' | b'\t' => true,
-        _ => false,
-    }
+    matches!(c, b' ' | b'\t')
 }
 
 pub fn is_int(c: u8, _is: Option<&mut IStream>) -> bool {
-    match c {
-        b'0' ..= b'9' => true,
-        _ => false,
-    }
+    c.is_ascii_digit()
 }
 
 pub fn is_quote(c: u8, _is: Option<&mut IStream>) -> bool {
@@ -19,23 +13,14 @@ pub fn is_quote(c: u8, _is: Option<&mut IStream>) -> bool {
 }
 
 pub fn is_kw_or_var(c: u8, _is: Option<&mut IStream>) -> bool {
-    match c {
-        b'a' ..= b'z' | b'A' ..= b'Z' | b'_' => true,
-        _ => false,
-    }
+    matches!(c, b'a' ..= b'z' | b'A' ..= b'Z' | b'_')
 }
 
 pub fn is_op(c: u8, _is: Option<&mut IStream>) -> bool {
-    match c {
-        b'=' | b'-' | b'+' | b'/' | b'*' | b'%' | b'!' => true,
-        _ => false,
-    }
+    matches!(c, b'=' | b'-' | b'+' | b'/' | b'*' | b'%' | b'!' | b'<' | b'>' | b'&' | b'|' | b'^' | b'~')
 }
 
 pub fn is_register_name(c: u8, _is: Option<&mut IStream>) -> bool {
-    match c {
-        b'a' ..= b'z' => true,
-        _ => false,
-    }
+    c.is_ascii_lowercase() || c.is_ascii_digit()
 }
 
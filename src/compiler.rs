@@ -1,4 +1,4 @@
-use crate::parser::{ScopeImpl, ScopeImplType, Statement, Expression};
+use crate::parser::{ScopeImpl, ScopeImplType, Statement, Expression, Condition, Address};
 
 use crate::tokenizer::{
     Type,
@@ -6,13 +6,65 @@ use crate::tokenizer::{
     Literal,
     Operator,
 };
+use crate::diagnostic::{self, Diagnostic};
+use crate::span::Span;
+use crate::register;
+use std::collections::{HashMap, HashSet};
 use std::str::from_utf8;
 
+// registers assigned the address of a constant, along with the constant and where
+type Pointers = HashMap<&'static str, (Vec<u8>, Span)>;
+
+// how the paths through a loop body that don't fall off its end leave the pointers
+#[derive(Default)]
+struct Exits {
+    breaks: Vec<Pointers>,
+    continues: Vec<Pointers>,
+}
+
+// a `let` inside a function, which lives on the stack at `rbp - offset`
+struct Local {
+    name: Vec<u8>,
+    offset: usize,
+    v_type: Type,
+    count: usize,
+}
+
 pub(crate) struct Program {
     pub header: Vec<u8>,
     pub text: Vec<u8>,
+    pub data: Vec<u8>,
     pub ro_data: Vec<u8>,
     pub bss: Vec<u8>,
+    label_counter: usize,
+    // element type of every global
+    types: HashMap<Vec<u8>, Type>,
+    // globals declared with `const`, which live in read only memory
+    constants: HashSet<Vec<u8>>,
+    // registers that point to a constant on every path to the statement being compiled
+    constant_pointers: Pointers,
+    // how many parameters every function takes
+    functions: HashMap<Vec<u8>, usize>,
+    // functions that are called but defined somewhere else, each declared `extern` once
+    externs: HashSet<Vec<u8>>,
+    // bytes of stack the function being compiled reserves for its locals, 0 when it doesn't need a frame
+    frame: usize,
+    // bytes the function being compiled moves rsp down by without a frame, so its calls are still aligned
+    padding: usize,
+    // lets of the function being compiled that are in scope, innermost last
+    locals: Vec<Local>,
+    // how far below rbp the last local allocated in the function ends
+    next_local: usize,
+    // callee saved registers the function being compiled pushes before anything else
+    saved: Vec<&'static str>,
+    // registers the function being compiled declared free for expressions
+    scratch: Vec<Vec<u8>>,
+    // (continue, break) labels of the loops being compiled, innermost last
+    loops: Vec<(String, String)>,
+    // label of the function's epilogue, made when the first `return` jumps to it, and how many do
+    epilogue: Option<(String, usize)>,
+    // warnings are left here when compiling succeeds
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl Program {
@@ -20,274 +72,1122 @@ impl Program {
         let mut p = Program {
             header: vec![],
             text: vec![],
+            data: vec![],
             ro_data: vec![],
             bss: vec![],
+            label_counter: 0,
+            types: HashMap::new(),
+            constants: HashSet::new(),
+            constant_pointers: HashMap::new(),
+            functions: HashMap::new(),
+            externs: HashSet::new(),
+            frame: 0,
+            padding: 0,
+            saved: vec![],
+            locals: vec![],
+            next_local: 0,
+            scratch: vec![],
+            loops: vec![],
+            epilogue: None,
+            diagnostics: vec![],
         };
         p.append_text(b"section .text\n");
         p.append_bss(b"section .bss\n");
         p.append_data(b"section .data\n");
+        p.append_ro_data(b"section .rodata\n");
         p
     }
-    pub(crate) fn compile(&mut self, scopes: Vec<ScopeImpl>) {
+    pub(crate) fn compile(&mut self, scopes: Vec<ScopeImpl>) -> Result<(), Vec<Diagnostic>> {
         let global = scopes.first().unwrap();
+        // globals can be used before they're declared, so their types are needed up front
         for statement in &global.scope {
-            match statement {
-                Statement::Scoped { scoped } => {
-                    match &scoped.scope_type {
-                        ScopeImplType::Fn { name, external } => {
-                            if *external {
-                                self.append_header(b"global ");
-                                self.append_header(name);
-                                self.append_header(b"\n");
-                            }
-                            self.append_text(name);
-                            self.append_text(b":\n");
-                            self.compile_scope(scoped);
-                            self.append_text(b"ret\n\n");
+            if let Statement::Scoped { scoped: ScopeImpl { scope_type: ScopeImplType::Fn { name, params, .. }, .. } } = statement {
+                self.functions.insert(name.clone(), params.len());
+                continue;
+            }
+            let (name, v_type) = match statement {
+                Statement::NewLet { name: Identifier::Variable(name), v_type, literal: None, .. } => (name, v_type.as_ref().map(|(t, _)| *t)),
+                Statement::NewLet { name: Identifier::Variable(name), v_type, literal: Some(literal), .. }
+                | Statement::NewConst { name: Identifier::Variable(name), v_type, literal, .. } => {
+                    (name, Some(v_type.as_ref().map_or(literal_type(literal), |(t, _)| *t)))
+                }
+                _ => continue,
+            };
+            if let Some(v_type) = v_type {
+                self.types.insert(name.clone(), v_type);
+            }
+            if let Statement::NewConst { .. } = statement {
+                self.constants.insert(name.clone());
+            }
+        }
+        for statement in &global.scope {
+            // an error only throws away the statement it came from
+            if let Err(d) = self.compile_global(statement) {
+                self.diagnostics.push(d);
+            }
+        }
+        if self.diagnostics.iter().any(Diagnostic::is_error) {
+            Err(std::mem::take(&mut self.diagnostics))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn compile_global(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
+        match statement {
+            Statement::Scoped { scoped } => {
+                if let ScopeImplType::Fn { name, external, scratch, uses, preserves, .. } = &scoped.scope_type {
+                    if *external {
+                        self.append_header(b"global ");
+                        self.append_header(name);
+                        self.append_header(b"\n");
+                    }
+                    self.append_text(name);
+                    self.append_text(b":\n");
+                    self.scratch = scratch.iter().map(|r| register::family(r).as_bytes().to_vec()).collect();
+                    self.constant_pointers.clear();
+                    self.check_preserved(scoped, uses, preserves);
+                    self.saved = uses.iter().map(|r| register::family(r)).collect();
+                    for r in self.saved.clone() {
+                        self.append_text(format!("push {}\n", r).as_ref());
+                    }
+                    // keeps rsp 16 byte aligned for calls, the return address, rbp and the saved registers make up the rest
+                    self.frame = frame_size(scoped);
+                    if self.frame > 0 {
+                        self.frame = self.frame.next_multiple_of(16) + self.saved.len() % 2 * 8;
+                    }
+                    // without a frame only the return address and the saved registers are on the stack
+                    self.padding = if self.frame == 0 && self.saved.len().is_multiple_of(2) && makes_calls(scoped) { 8 } else { 0 };
+                    self.next_local = 0;
+                    self.epilogue = None;
+                    if self.frame > 0 {
+                        self.append_text(format!("push rbp\nmov rbp, rsp\nsub rsp, {}\n", self.frame).as_ref());
+                    }
+                    if self.padding > 0 {
+                        self.append_text(format!("sub rsp, {}\n", self.padding).as_ref());
+                    }
+                    self.compile_scope(scoped);
+                    if let Some((epilogue, mut returns)) = self.epilogue.take() {
+                        // a return at the very end falls through to the epilogue instead of jumping to it
+                        let jump = format!("jmp {}\n", epilogue);
+                        if self.text.ends_with(jump.as_bytes()) {
+                            self.text.truncate(self.text.len() - jump.len());
+                            returns -= 1;
+                        }
+                        if returns > 0 {
+                            self.append_text(format!("{}:\n", epilogue).as_ref());
                         }
-                        _ => {}
                     }
+                    self.compile_epilogue();
+                    self.append_text(b"\n");
                 }
-                // lets are mutable
-                Statement::NewLet { name, literal, v_type } => {
-                    let v_name = if let Identifier::Variable(v_name) = name {
-                        v_name
-                    } else {
-                        panic!("let name is bad");
-                    };
-                    match literal {
-                        Some(l) => {}
-                        None => {
-                            let (vt, size) = if let Some(vt) = v_type {
-                                vt
-                            } else {
-                                panic!("need type for uninitialized");
-                            };
-                            let v8_name = from_utf8(v_name).unwrap();
-                            match vt {
-                                Type::Uint8 => {
-                                    self.append_bss(format!("{}: resb {}\n", v8_name, size).as_ref());
-                                },
-                                Type::Uint64 => {
-                                    self.append_bss(format!("{}: resq {}\n", v8_name, size).as_ref());
-                                }
+            }
+            // lets are mutable
+            Statement::NewLet { name, literal, v_type, span } => {
+                let v_name = if let Identifier::Variable(v_name) = name {
+                    v_name
+                } else {
+                    unreachable!("the parser only names lets with variables");
+                };
+                match literal {
+                    Some(literal) => {
+                        let data = literal_data(v_name, literal, *v_type, *span)?;
+                        self.append_data(data.as_ref());
+                    }
+                    None => {
+                        let (vt, size) = v_type.as_ref().ok_or_else(|| missing_type(v_name, *span))?;
+                        let v8_name = from_utf8(v_name).unwrap();
+                        match vt {
+                            Type::Uint8 => {
+                                self.append_bss(format!("{}: resb {}\n", v8_name, size).as_ref());
+                            },
+                            Type::Uint16 => {
+                                self.append_bss(format!("{}: resw {}\n", v8_name, size).as_ref());
+                            }
+                            Type::Uint32 => {
+                                self.append_bss(format!("{}: resd {}\n", v8_name, size).as_ref());
+                            }
+                            Type::Uint64 => {
+                                self.append_bss(format!("{}: resq {}\n", v8_name, size).as_ref());
                             }
-                            self.append_bss(format!("{}_LEN equ $ - {}\n", v8_name.to_uppercase(), v8_name).as_ref());
                         }
+                        self.append_bss(format!("{}_LEN equ $ - {}\n", v8_name.to_uppercase(), v8_name).as_ref());
                     }
                 }
-                Statement::NewConst { name, literal, v_type } => {
-                    let v_name = if let Identifier::Variable(v_name) = name {
-                        v_name
-                    } else {
-                        panic!("const name is bad");
-                    };
-                    match literal {
-                        Literal::String(s) => {
-                            let v8_name = from_utf8(v_name).unwrap();
-                            let s8 = from_utf8(s).unwrap();
-                            self.append_data(format!("{}: db \"{}\", 10\n", v8_name, s8).as_ref());
-                            self.append_data(format!("{}_LEN equ $ - {}\n", v8_name.to_uppercase(), v8_name).as_ref());
-                        }
-                        _ => {}
-                    };
-                }
-                _ => {}
             }
-        };
+            Statement::NewConst { name, literal, v_type, span } => {
+                let v_name = if let Identifier::Variable(v_name) = name {
+                    v_name
+                } else {
+                    unreachable!("the parser only names consts with variables");
+                };
+                let data = literal_data(v_name, literal, *v_type, *span)?;
+                self.append_ro_data(data.as_ref());
+            }
+            _ => return Err(Diagnostic::error(diagnostic::UNSUPPORTED_STATEMENT, "statement is not allowed outside of a function")
+                .with_primary(statement.span(), "move this into a function")),
+        }
+        Ok(())
     }
 
     fn compile_scope(&mut self, f: &ScopeImpl) {
-        let scope = &f.scope;
-        let mut scope_counter: usize = 0;
-        for statement in scope {
-            match statement {
-                Statement::InlineAssembly { instructions } => {
-                    self.append_text(instructions);
-                    self.append_text(b"\n");
+        // locals go out of scope with the scope they're declared in
+        let depth = self.locals.len();
+        for statement in &f.scope {
+            // like globals, a bad statement doesn't stop the rest of the scope
+            if let Err(d) = self.compile_statement(statement) {
+                self.report(d);
+            }
+        }
+        self.locals.truncate(depth);
+    }
+
+    // loops are checked more than once, so the same error can turn up again
+    fn report(&mut self, d: Diagnostic) {
+        if !self.diagnostics.iter().any(|e| e.code == d.code && e.primary_span() == d.primary_span()) {
+            self.diagnostics.push(d);
+        }
+    }
+
+    // tears down the frame, if there is one, and returns. every way out of a function ends up here
+    fn compile_epilogue(&mut self) {
+        if self.frame > 0 {
+            self.append_text(b"mov rsp, rbp\npop rbp\n");
+        }
+        if self.padding > 0 {
+            self.append_text(format!("add rsp, {}\n", self.padding).as_ref());
+        }
+        for r in self.saved.clone().iter().rev() {
+            self.append_text(format!("pop {}\n", r).as_ref());
+        }
+        self.append_text(b"ret\n");
+    }
+
+    // warns about writes to registers the function promised to preserve, unless it saves them anyway
+    fn check_preserved(&mut self, function: &ScopeImpl, uses: &[Vec<u8>], preserves: &[Vec<u8>]) {
+        let saved: Vec<&str> = uses.iter().map(|r| register::family(r)).collect();
+        let preserved: Vec<&[u8]> = preserves.iter().map(|r| r.as_slice()).filter(|r| !saved.contains(&register::family(r))).collect();
+        let mut warnings = vec![];
+        function.each_statement(&mut |statement| {
+            let (written, span) = match statement {
+                Statement::RegisterAssign { register, span, .. } => (register::family(register), *span),
+                Statement::Return { expression: Some(_), span } => ("rax", *span),
+                _ => return,
+            };
+            if let Some(r) = preserved.iter().find(|r| register::family(r) == written) {
+                warnings.push(Diagnostic::warning(diagnostic::WRITES_PRESERVED, format!("`{}` is written, but the function preserves it", from_utf8(r).unwrap()))
+                    .with_primary(span, format!("writes `{}`", written))
+                    .with_note("save it with `uses(...)` if it's callee saved, or take it out of `preserves(...)`"));
+            }
+        });
+        self.diagnostics.extend(warnings);
+    }
+
+    // a `let` in a function gets the next slot on the stack, and is initialized there if it has a value
+    fn compile_local(&mut self, name: &[u8], literal: Option<&Literal>, description: Option<(Type, usize)>, span: Span) -> Result<(), Diagnostic> {
+        let (v_type, count, values) = match literal {
+            Some(literal) => literal_layout(name, literal, description, span)?,
+            None => {
+                let (v_type, count) = description.ok_or_else(|| missing_type(name, span))?;
+                (v_type, count, vec![])
+            }
+        };
+        self.next_local = allocate(self.next_local, v_type, count);
+        let offset = self.next_local;
+        if literal.is_some() {
+            let size = v_type.size();
+            let mut bytes: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()[..size].to_vec()).collect();
+            bytes.resize(size * count, 0);
+            // stored in the widest pieces that fit, so a long string isn't a store per byte
+            let mut at = 0;
+            while at < bytes.len() {
+                let width = [Type::Uint64, Type::Uint32, Type::Uint16, Type::Uint8].iter().copied().find(|w| w.size() <= bytes.len() - at).unwrap();
+                let mut piece = [0; 8];
+                piece[..width.size()].copy_from_slice(&bytes[at..at + width.size()]);
+                let address = Address { base: Some(b"rbp".to_vec()), displacement: at as i64 - offset as i64, ..Address::default() };
+                self.compile_store(&address, width, &Expression::Number { value: u64::from_le_bytes(piece) as usize, span }, span)?;
+                at += width.size();
+            }
+        }
+        self.locals.push(Local { name: name.to_vec(), offset, v_type, count });
+        Ok(())
+    }
+
+    // the innermost local called `name`
+    fn local(&self, name: &[u8]) -> Option<&Local> {
+        self.locals.iter().rev().find(|local| local.name == name)
+    }
+
+    // the type of each element of a local or global
+    fn element_type(&self, name: &[u8]) -> Option<Type> {
+        self.local(name).map(|local| local.v_type).or_else(|| self.types.get(name).copied())
+    }
+
+    fn compile_store(&mut self, address: &Address, width: Type, expression: &Expression, span: Span) -> Result<(), Diagnostic> {
+        // caught here, or the error would name whatever scratch register the address ended up in
+        if let Some(variable) = address_in(expression).filter(|_| width < Type::Uint32) {
+            return Err(Diagnostic::error(diagnostic::UNSUPPORTED_OPERATION, format!("a {} store can't hold an address", width))
+                .with_primary(variable, "an address")
+                .with_secondary(span, "in this store")
+                .with_note("store it as a qword with `:qword`, like `$*rdi:qword = buf`"));
+        }
+        let mut used: Vec<Vec<u8>> = address.registers().cloned().collect();
+        registers_read(expression, &mut used);
+        let mut scratch = self.scratch(used);
+        let src = self.compile_operand(expression, width, &mut scratch).map_err(|d| d.with_secondary(span, "in this store"))?;
+        self.append_text(format!("mov {} [{}], {}\n", width, address_data(address), Program::expression_data(src, width)?).as_ref());
+        self.restore_scratch(scratch);
+        Ok(())
+    }
+
+    fn compile_statement(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
+        match statement {
+            Statement::InlineAssembly { instructions, .. } => {
+                // there's no telling what the assembly writes
+                self.constant_pointers.clear();
+                self.append_text(instructions);
+                self.append_text(b"\n");
+            }
+            Statement::RegisterAssign { register, expression, span } => {
+                let expression = self.fold(expression)?;
+                match self.constant_pointer(&expression, &self.constant_pointers, *span) {
+                    Some(pointer) => {
+                        self.constant_pointers.insert(register::family(register), pointer);
+                    }
+                    None => {
+                        self.constant_pointers.remove(register::family(register));
+                    }
                 }
-                Statement::RegisterAssign { register, expression } => {
-                    match expression {
-                        Expression::Variable { var } => {
-                            self.append_text(b"mov ");
-                            self.append_text(register);
-                            self.append_text(b", ");
-                            self.append_text(var);
-                            self.append_text(b"\n");
-                        }
-                        _ => {
-                            let (data_reg, expr_code) = self.compile_expression(expression.clone());
-                            self.append_text(&*expr_code);
-                            let src = Program::expression_data(data_reg);
-                            self.append_text(format!("mov {}, {}\n", from_utf8(register).unwrap(), src).as_ref());
-                        }
+                let mut used = vec![register::family(register).as_bytes().to_vec()];
+                registers_read(&expression, &mut used);
+                let mut scratch = self.scratch(used);
+                self.compile_into(&expression, register, &mut scratch)?;
+                self.restore_scratch(scratch);
+            },
+            Statement::RegisterDerefAssign { address, width, expression, span } => {
+                self.check_writable(address, &self.constant_pointers, *span)?;
+                let (address, element) = self.resolve(address, *span)?;
+                // stores are a byte unless they say otherwise
+                let width = width.or(element).unwrap_or(Type::Uint8);
+                let expression = self.fold(expression)?;
+                self.compile_store(&address, width, &expression, *span)?;
+            }
+            Statement::NewLet { name: Identifier::Variable(name), literal, v_type, span } => {
+                self.compile_local(name, literal.as_ref(), *v_type, *span)?;
+            }
+            Statement::Call { f, arguments, span } => {
+                match self.functions.get(f) {
+                    Some(&count) if count != arguments.len() => {
+                        return Err(Diagnostic::error(diagnostic::WRONG_ARGUMENT_COUNT, format!(
+                            "`{}` takes {} argument{} but {} {} given",
+                            from_utf8(f).unwrap(), count, if count == 1 { "" } else { "s" }, arguments.len(), if arguments.len() == 1 { "was" } else { "were" },
+                        ))
+                            .with_primary(*span, format!("expected {}", count)));
                     }
-                },
-                // no time to optimize so it's duplicate code for now
-                Statement::RegisterDerefAssign { register, expression } => {
-                    match expression {
-                        Expression::Variable { var } => {
-                            self.append_text(b"mov [");
-                            self.append_text(register);
-                            self.append_text(b"], ");
-                            self.append_text(var);
-                            self.append_text(b"\n");
-                        }
-                        _ => {
-                            let (data_reg, expr_code) = self.compile_expression(expression.clone());
-                            self.append_text(&*expr_code);
-                            let src = Program::expression_data(data_reg);
-                            self.append_text(format!("mov byte [{}], {}\n", from_utf8(register).unwrap(), src).as_ref());
+                    Some(_) => {}
+                    // nasm won't assemble a call to a symbol it hasn't heard of
+                    None => {
+                        if self.externs.insert(f.clone()) {
+                            self.append_header(format!("extern {}\n", from_utf8(f).unwrap()).as_ref());
                         }
                     }
                 }
-                Statement::Call { f } => {
-                    self.append_text(b"call ");
-                    self.append_text(f);
-                    self.append_text(b"\n");
+                self.compile_arguments(arguments, *span)?;
+                self.constant_pointers.clear();
+                self.append_text(b"call ");
+                self.append_text(f);
+                self.append_text(b"\n");
+            }
+            Statement::Return { expression, .. } => {
+                if let Some(expression) = expression {
+                    let expression = self.fold(expression)?;
+                    let mut used = vec![b"rax".to_vec()];
+                    registers_read(&expression, &mut used);
+                    let mut scratch = self.scratch(used);
+                    self.compile_into(&expression, b"rax", &mut scratch)?;
+                    self.restore_scratch(scratch);
                 }
-                Statement::Scoped { scoped } => {
-                    match &scoped.scope_type {
-                        ScopeImplType::If {
-                            left, right, condition
-                        } => {
-                            let (left_src, left_code) = self.compile_expression(left.clone());
-                            let (right_src, right_code) = self.compile_expression(right.clone());
-                            self.append_text(&left_code);
-                            self.append_text(&right_code);
-
-                            self.append_text(format!("cmp {}, {}\n", Program::expression_data(left_src), Program::expression_data(right_src)).as_ref());
-                            match condition {
-                                Operator::NotEqual => {
-                                    self.append_text(format!("je ._{}_\n", scope_counter).as_ref());
-                                },
-                                Operator::Equal => {
-                                    self.append_text(format!("jne ._{}_\n", scope_counter).as_ref());
-                                }
-                                _ => {}
-                            }
-                            self.compile_scope(scoped);
-                            self.append_text(format!("._{}_:\n", scope_counter).as_ref());
-                            scope_counter += 1;
-                        },
-                        _ => {}
+                if self.epilogue.is_none() {
+                    self.epilogue = Some((self.new_label(), 0));
+                }
+                let (epilogue, returns) = self.epilogue.as_mut().unwrap();
+                *returns += 1;
+                let jump = format!("jmp {}\n", epilogue);
+                self.append_text(jump.as_ref());
+            }
+            Statement::Break { .. } => {
+                // the parser only allows break and continue inside of loops
+                let (_, end) = self.loops.last().unwrap();
+                self.append_text(format!("jmp {}\n", end).as_ref());
+            }
+            Statement::Continue { .. } => {
+                let (top, _) = self.loops.last().unwrap();
+                self.append_text(format!("jmp {}\n", top).as_ref());
+            }
+            Statement::Scoped { scoped } => {
+                // labels are local to the function, but numbered across the program so nested scopes don't collide
+                match &scoped.scope_type {
+                    ScopeImplType::If { .. } => self.compile_if(scoped, None)?,
+                    ScopeImplType::While { condition } => {
+                        let top = self.new_label();
+                        let end = self.new_label();
+                        self.append_text(format!("{}:\n", top).as_ref());
+                        self.compile_condition(condition, &end, false)?;
+                        self.compile_loop_body(scoped, top, end);
+                    }
+                    ScopeImplType::Loop => {
+                        let top = self.new_label();
+                        let end = self.new_label();
+                        self.append_text(format!("{}:\n", top).as_ref());
+                        self.compile_loop_body(scoped, top, end);
                     }
+                    _ => {}
+                }
+            }
+            _ => return Err(Diagnostic::error(diagnostic::UNSUPPORTED_STATEMENT, "statement is not allowed inside a function")
+                .with_primary(statement.span(), "move this outside of the function")),
+        }
+        Ok(())
+    }
+
+    // moves every argument into its register. an argument register is only written once no other argument
+    // still reads it, and when they all do, like `call(f, $rsi, $rdi)`, one is computed somewhere else first
+    fn compile_arguments(&mut self, arguments: &[Expression], span: Span) -> Result<(), Diagnostic> {
+        let arguments = arguments.iter().map(|argument| self.fold(argument)).collect::<Result<Vec<_>, _>>()?;
+        let targets = &register::ARGUMENTS[..arguments.len()];
+        let mut used: Vec<Vec<u8>> = targets.iter().map(|r| r.as_bytes().to_vec()).collect();
+        for argument in &arguments {
+            registers_read(argument, &mut used);
+        }
+        let mut scratch = self.scratch(used);
+        let mut pending: Vec<usize> = (0..arguments.len()).collect();
+        let mut parked = vec![];
+        while !pending.is_empty() {
+            let ready = pending.iter().position(|&i| !pending.iter().any(|&j| j != i && reads(&arguments[j], targets[i].as_bytes())));
+            match ready {
+                Some(k) => {
+                    let i = pending.remove(k);
+                    self.compile_into(&arguments[i], targets[i].as_bytes(), &mut scratch)?;
+                }
+                None => {
+                    let i = pending.remove(0);
+                    let temp = self.take_scratch(&mut scratch, span)?;
+                    self.compile_into(&arguments[i], &temp, &mut scratch)?;
+                    parked.push((i, temp));
+                }
+            }
+        }
+        for (i, temp) in parked {
+            self.append_text(format!("mov {}, {}\n", targets[i], from_utf8(&temp).unwrap()).as_ref());
+            release(&mut scratch, Expression::Register { reg: temp, span });
+        }
+        self.restore_scratch(scratch);
+        Ok(())
+    }
+
+    // every branch of an if / else if / else chain jumps to the same `end` label when it's done
+    fn compile_if(&mut self, scoped: &ScopeImpl, end: Option<String>) -> Result<(), Diagnostic> {
+        let (condition, else_scope) = match &scoped.scope_type {
+            ScopeImplType::If { condition, else_scope } => (condition, else_scope),
+            _ => unreachable!("only ifs start a chain"),
+        };
+        let skip = self.new_label();
+        self.compile_condition(condition, &skip, false)?;
+        // each branch starts with the pointers from before the if, and afterwards only the ones they all agree on are left
+        let before = self.constant_pointers.clone();
+        self.compile_scope(scoped);
+        let taken = std::mem::replace(&mut self.constant_pointers, before);
+        match else_scope {
+            None => {
+                self.append_text(format!("{}:\n", skip).as_ref());
+                if let Some(end) = end {
+                    self.append_text(format!("{}:\n", end).as_ref());
+                }
+            }
+            Some(else_scope) => {
+                let end = end.unwrap_or_else(|| self.new_label());
+                self.append_text(format!("jmp {}\n", end).as_ref());
+                self.append_text(format!("{}:\n", skip).as_ref());
+                if let ScopeImplType::Else = else_scope.scope_type {
+                    self.compile_scope(else_scope);
+                    self.append_text(format!("{}:\n", end).as_ref());
+                } else {
+                    self.compile_if(else_scope, Some(end))?;
                 }
-                _ => {}
             }
         }
+        let skipped = std::mem::take(&mut self.constant_pointers);
+        self.constant_pointers = join(Some(taken), Some(skipped)).unwrap();
+        Ok(())
     }
 
     /*
-    consider most basic case:
-        +
-      /   \
-     rax  1
-     result will be in rax, instructions will be
-     add rax, 1
-     sub is the same
-         *
-       /   \
-      rax  2
-     will work out to be
-     lea rax, [rax*2]
-     as such, for larger expressions we could just simplify something like
-           +
-         /   \
-       rax   *
-           /  \
-          rbx  3
-     into:
-     lea rbx, [rbx*3] and
-        +
-      /  \
-     rax rbx
+    jumps to `target` when the condition evaluates to `when`, falls through otherwise.
+    && and || short circuit, so for `a && b` with when = true we get
+    <a> false -> skip
+    <b> true -> target
+    skip:
      */
-    fn compile_expression(&mut self, expr: Expression) -> (Expression, Vec<u8>) {
-        let mut instructions: Vec<u8> = vec![];
-        let expr_copy = expr.clone();
-        (match expr {
-            Expression::Binary { left, right, operator } => {
-                let (atom_left, left_code) = self.compile_expression(*left);
-                let (atom_right, right_code) = self.compile_expression(*right);
-                instructions.append(&mut right_code.clone());
-                instructions.append(&mut left_code.clone());
-                match (atom_left, atom_right) {
-                    (Expression::Number { value: v1 }, Expression::Number { value: v2 }) => {
-                        // cant create asm code for 2 numbers together so just optimize
-                        Expression::Number { value: Program::operator_function(operator)(v1, v2) }
+    fn compile_condition(&mut self, condition: &Condition, target: &str, when: bool) -> Result<(), Diagnostic> {
+        match condition {
+            Condition::Compare { left, right, relation, .. } => self.compile_comparison(left, right, relation, target, when)?,
+            Condition::Not { operand, .. } => self.compile_condition(operand, target, !when)?,
+            // `a && b` is false as soon as a is, `a || b` true as soon as a is
+            Condition::And { left, right, .. } | Condition::Or { left, right, .. } => {
+                let short_circuit = matches!(condition, Condition::Or { .. });
+                if short_circuit == when {
+                    self.compile_condition(left, target, when)?;
+                    self.compile_condition(right, target, when)?;
+                } else {
+                    let skip = self.new_label();
+                    self.compile_condition(left, &skip, short_circuit)?;
+                    self.compile_condition(right, target, when)?;
+                    self.append_text(format!("{}:\n", skip).as_ref());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_comparison(&mut self, left: &Expression, right: &Expression, condition: &Operator, target: &str, when: bool) -> Result<(), Diagnostic> {
+        let left = self.fold(left)?;
+        let right = self.fold(right)?;
+        let (left, right, condition) = match (left, right) {
+            (Expression::Number { value: l, .. }, Expression::Number { value: r, .. }) => {
+                // nothing to compare at runtime, the branch is either always or never taken
+                if Program::relation_function(condition)(l, r) == when {
+                    self.append_text(format!("jmp {}\n", target).as_ref());
+                }
+                return Ok(());
+            }
+            // cmp can't take an immediate on the left, so flip it around
+            (left @ Expression::Number { .. }, right) => (right, left, mirror(condition)),
+            (left, right) => (left, right, condition.clone()),
+        };
+        let mut used = vec![];
+        registers_read(&left, &mut used);
+        registers_read(&right, &mut used);
+        let mut scratch = self.scratch(used);
+        // compared as wide as the widest side, or a qword when neither has a width
+        let width = self.natural_width(&left).max(self.natural_width(&right)).unwrap_or(Type::Uint64);
+        let left = match left {
+            Expression::Register { ref reg, .. } if register::width(reg) == width => left,
+            _ => {
+                let t = register::sized(&self.take_scratch(&mut scratch, left.span())?, width).as_bytes().to_vec();
+                self.compile_into(&left, &t, &mut scratch)?;
+                Expression::Register { reg: t, span: left.span() }
+            }
+        };
+        let right = self.compile_operand(&right, width, &mut scratch)?;
+        self.append_text(format!("cmp {}, {}\n", Program::expression_data(left, width)?, Program::expression_data(right, width)?).as_ref());
+        // the pops don't touch the flags cmp just set
+        self.restore_scratch(scratch);
+        self.append_text(format!("{} {}\n", jump(&condition, when), target).as_ref());
+        Ok(())
+    }
+
+    // the body jumps back to `top` when it's done, and `end` is placed right after it
+    fn compile_loop_body(&mut self, scoped: &ScopeImpl, top: String, end: String) {
+        // the top of the loop is also reached from the bottom, so only pointers both ways in agree on are kept
+        let entry = std::mem::take(&mut self.constant_pointers);
+        let head = self.loop_head(scoped, &entry);
+        let mut errors = vec![];
+        self.check_next_pass(scoped, &entry, &mut errors);
+        self.constant_pointers = head.clone();
+        self.loops.push((top, end));
+        self.compile_scope(scoped);
+        let (top, end) = self.loops.pop().unwrap();
+        self.append_text(format!("jmp {}\n", top).as_ref());
+        self.append_text(format!("{}:\n", end).as_ref());
+        for d in errors {
+            self.report(d);
+        }
+        self.constant_pointers = self.after_loop(scoped, head).unwrap_or_default();
+    }
+
+    /*
+    follows which registers point to constants through a scope without compiling it, so a loop can know what
+    its body leaves behind before the body is compiled. gives None when the scope never reaches its end, and
+    stores through a pointer to a constant go in `errors`
+     */
+    fn follow(&self, scope: &ScopeImpl, mut pointers: Pointers, exits: &mut Exits, errors: &mut Vec<Diagnostic>) -> Option<Pointers> {
+        for statement in &scope.scope {
+            match statement {
+                Statement::RegisterAssign { register, expression, span } => {
+                    match self.fold(expression).ok().and_then(|e| self.constant_pointer(&e, &pointers, *span)) {
+                        Some(pointer) => pointers.insert(register::family(register), pointer),
+                        None => pointers.remove(register::family(register)),
+                    };
+                }
+                Statement::RegisterDerefAssign { address, span, .. } => {
+                    if let Err(d) = self.check_writable(address, &pointers, *span) {
+                        errors.push(d);
                     }
-                    (Expression::Register { reg }, Expression::Number { value })
-                    | (Expression::Number { value }, Expression::Register { reg }) => {
-                        // create code to apply an imm to a register using the op
-                        instructions.append(&mut Program::operator_reg_imm(operator, &reg, value));
-                        Expression::Register { reg }
+                }
+                Statement::InlineAssembly { .. } | Statement::Call { .. } => pointers.clear(),
+                Statement::Return { .. } => return None,
+                Statement::Break { .. } => {
+                    exits.breaks.push(pointers);
+                    return None;
+                }
+                Statement::Continue { .. } => {
+                    exits.continues.push(pointers);
+                    return None;
+                }
+                Statement::Scoped { scoped } => pointers = self.follow_scoped(scoped, pointers, exits, errors)?,
+                _ => {}
+            }
+        }
+        Some(pointers)
+    }
+
+    fn follow_scoped(&self, scoped: &ScopeImpl, pointers: Pointers, exits: &mut Exits, errors: &mut Vec<Diagnostic>) -> Option<Pointers> {
+        match &scoped.scope_type {
+            ScopeImplType::If { else_scope, .. } => {
+                let taken = self.follow(scoped, pointers.clone(), exits, errors);
+                let skipped = match else_scope {
+                    Some(else_scope) => self.follow_scoped(else_scope, pointers, exits, errors),
+                    None => Some(pointers),
+                };
+                join(taken, skipped)
+            }
+            ScopeImplType::While { .. } | ScopeImplType::Loop => {
+                let head = self.loop_head(scoped, &pointers);
+                self.check_next_pass(scoped, &pointers, errors);
+                self.follow(scoped, head.clone(), &mut Exits::default(), errors);
+                self.after_loop(scoped, head)
+            }
+            _ => self.follow(scoped, pointers, exits, errors),
+        }
+    }
+
+    // what the loop body leaves behind when it goes around again, None if it never does
+    fn back_edge(&self, body: &ScopeImpl, pointers: Pointers) -> Option<Pointers> {
+        let mut exits = Exits::default();
+        let end = self.follow(body, pointers, &mut exits, &mut vec![]);
+        exits.continues.into_iter().map(Some).fold(end, join)
+    }
+
+    // the pointers every pass through a loop starts with, found by going around until nothing more is dropped
+    fn loop_head(&self, body: &ScopeImpl, entry: &Pointers) -> Pointers {
+        let mut head = entry.clone();
+        loop {
+            let next = join(Some(entry.clone()), self.back_edge(body, head.clone())).unwrap();
+            if next.len() == head.len() {
+                return next;
+            }
+            head = next;
+        }
+    }
+
+    // the second pass starts with whatever the first left behind, which can point somewhere the first didn't
+    fn check_next_pass(&self, body: &ScopeImpl, entry: &Pointers, errors: &mut Vec<Diagnostic>) {
+        if let Some(pointers) = self.back_edge(body, entry.clone()) {
+            let count = errors.len();
+            self.follow(body, pointers, &mut Exits::default(), errors);
+            for d in &mut errors[count..] {
+                d.notes.push("this happens from the loop's second pass on".to_string());
+            }
+        }
+    }
+
+    // a while loop is left from its top or by a break, a plain loop only by a break
+    fn after_loop(&self, body: &ScopeImpl, head: Pointers) -> Option<Pointers> {
+        let mut exits = Exits::default();
+        self.follow(body, head.clone(), &mut exits, &mut vec![]);
+        let top = match body.scope_type {
+            ScopeImplType::While { .. } => Some(head),
+            _ => None,
+        };
+        exits.breaks.into_iter().map(Some).fold(top, join)
+    }
+
+    /*
+    computes `expr` into `dest` without touching any register the expression reads.
+    the left side is computed straight into dest, then the right side is applied to it:
+        $rdi = $rsi + $rax - 1
+    works out to be
+        mov rdi, rsi
+        add rdi, rax
+        sub rdi, 1
+    when the right side reads dest it has to be computed first, into a scratch register
+        $rax = $rbx - $rax
+        mov r11, rax
+        mov rax, rbx
+        sub rax, r11
+    everything is computed as wide as dest, so wider registers are cut down and narrower ones zero extended
+     */
+    fn compile_into(&mut self, expr: &Expression, dest: &[u8], scratch: &mut Scratch) -> Result<(), Diagnostic> {
+        let reg = from_utf8(dest).unwrap();
+        let width = register::width(dest);
+        match expr {
+            Expression::Number { value, span } => {
+                check_immediate(*value, width, *span)?;
+                self.append_text(format!("mov {}, {}\n", reg, immediate_data(*value, width)).as_ref());
+            }
+            Expression::Register { reg: src, .. } => {
+                let src_width = register::width(src);
+                if src_width >= width {
+                    let src = register::sized(src, width);
+                    if src.as_bytes() != dest {
+                        self.append_text(format!("mov {}, {}\n", reg, src).as_ref());
                     }
-                    (Expression::Register { reg: r1 }, Expression::Register { reg: r2 }) => {
-                        instructions.append(&mut Program::operator_reg_reg(operator, &r1, &r2));
-                        Expression::Register { reg: r1 }
+                } else if src_width == Type::Uint32 {
+                    // writing a dword register clears the top half by itself
+                    self.append_text(format!("mov {}, {}\n", register::sized(dest, Type::Uint32), from_utf8(src).unwrap()).as_ref());
+                } else {
+                    self.append_text(format!("movzx {}, {}\n", reg, from_utf8(src).unwrap()).as_ref());
+                }
+            }
+            Expression::Variable { var, span } => {
+                if width < Type::Uint32 {
+                    return Err(Diagnostic::error(diagnostic::UNSUPPORTED_OPERATION, "addresses don't fit in a byte or word register")
+                        .with_primary(*span, format!("can't be put in `{}`", reg)));
+                }
+                match self.local(var) {
+                    Some(local) => self.append_text(format!("lea {}, [rbp - {}]\n", reg, local.offset).as_ref()),
+                    None => self.append_text(format!("mov {}, {}\n", reg, from_utf8(var).unwrap()).as_ref()),
+                }
+            }
+            Expression::Load { address, width: load_width, span } => {
+                let (address, element) = self.resolve(address, *span)?;
+                let load_width = load_width.or(element);
+                let addr = address_data(&address);
+                // loading more than fits only takes the low part, which comes first in memory
+                match load_width.filter(|w| *w < width) {
+                    None => self.append_text(format!("mov {}, {} [{}]\n", reg, width, addr).as_ref()),
+                    Some(Type::Uint32) => self.append_text(format!("mov {}, dword [{}]\n", register::sized(dest, Type::Uint32), addr).as_ref()),
+                    Some(load_width) => self.append_text(format!("movzx {}, {} [{}]\n", reg, load_width, addr).as_ref()),
+                }
+            }
+            Expression::Binary { left, right, operator, span } => {
+                // `1 + $rax` into rax is better off as `$rax + 1`
+                let (left, right) = if commutative(operator) && reads(right, dest) && !reads(left, dest) {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+                let src = if reads(right, dest) {
+                    let t = register::sized(&self.take_scratch(scratch, *span)?, width).as_bytes().to_vec();
+                    self.compile_into(right, &t, scratch)?;
+                    self.compile_into(left, dest, scratch)?;
+                    Expression::Register { reg: t, span: right.span() }
+                } else {
+                    self.compile_into(left, dest, scratch)?;
+                    self.compile_operand(right, width, scratch)?
+                };
+                if divides(operator) {
+                    self.compile_division(operator, dest, src, scratch, *span)?;
+                } else if let (true, Expression::Register { reg: count, .. }) = (shifts(operator), &src) {
+                    self.compile_shift(operator, dest, count.clone(), scratch, *span)?;
+                } else {
+                    let code = match &src {
+                        Expression::Number { value, .. } => Program::operator_reg_imm(operator, dest, *value)
+                            .map_err(|d| d.with_primary(*span, "between this register and number"))?,
+                        Expression::Register { reg: src, .. } => Program::operator_reg_reg(operator, dest, src)
+                            .map_err(|d| d.with_primary(*span, "between these registers"))?,
+                        _ => unreachable!("operands are numbers or registers"),
+                    };
+                    self.append_text(&code);
+                    release(scratch, src);
+                }
+            }
+            Expression::Unary { operand, operator, span } => {
+                self.compile_into(operand, dest, scratch)?;
+                let code = Program::operator_reg(operator, dest).map_err(|d| d.with_primary(*span, "on this"))?;
+                self.append_text(&code);
+            }
+        }
+        Ok(())
+    }
+
+    // shifting by a register only takes the count in cl
+    fn compile_shift(&mut self, operator: &Operator, dest: &[u8], count: Vec<u8>, scratch: &mut Scratch, span: Span) -> Result<(), Diagnostic> {
+        let instruction = shift_instruction(operator);
+        let reg = from_utf8(dest).unwrap();
+        let count8 = register::sized(&count, Type::Uint8);
+        if register::family(&count) == "rcx" {
+            self.append_text(format!("{} {}, cl\n", instruction, reg).as_ref());
+        } else if register::family(dest) == "rcx" {
+            // rcx can't be shifted by itself, so shift a copy
+            let t = self.take_scratch_except(scratch, span, &["rcx"])?;
+            let t = register::sized(&t, register::width(dest));
+            self.append_text(format!("mov {}, {}\n", t, reg).as_ref());
+            self.append_text(format!("mov cl, {}\n", count8).as_ref());
+            self.append_text(format!("{} {}, cl\n", instruction, t).as_ref());
+            self.append_text(format!("mov {}, {}\n", reg, t).as_ref());
+            release(scratch, Expression::Register { reg: t.as_bytes().to_vec(), span });
+        } else {
+            let save = !scratch.free.iter().any(|r| r == b"rcx");
+            if save {
+                self.append_text(b"push rcx\n");
+            }
+            self.append_text(format!("mov cl, {}\n", count8).as_ref());
+            self.append_text(format!("{} {}, cl\n", instruction, reg).as_ref());
+            if save {
+                self.append_text(b"pop rcx\n");
+            }
+        }
+        release(scratch, Expression::Register { reg: count, span });
+        Ok(())
+    }
+
+    /*
+    div and idiv divide rdx:rax, leaving the quotient in rax and the remainder in rdx.
+    both are saved around the division unless they're the destination or free anyway:
+        $rcx = $rcx / 10
+        mov r11, 10
+        push rax
+        push rdx
+        mov rax, rcx
+        xor edx, edx
+        div r11
+        mov rcx, rax
+        pop rdx
+        pop rax
+    narrower divisions use the same parts of rax and rdx, except for bytes which work differently
+     */
+    fn compile_division(&mut self, operator: &Operator, dest: &[u8], divisor: Expression, scratch: &mut Scratch, span: Span) -> Result<(), Diagnostic> {
+        let width = register::width(dest);
+        if width == Type::Uint8 {
+            return Err(unsupported(operator, "on byte registers").with_primary(span, "computed as a byte"));
+        }
+        if let Expression::Number { value: 0, span } = divisor {
+            return Err(division_by_zero(span));
+        }
+        // the divisor has to be in a register, and not one of the two being overwritten
+        let divisor = match divisor {
+            Expression::Register { reg, .. } if !matches!(register::family(&reg), "rax" | "rdx") => reg,
+            _ => {
+                let t = self.take_scratch_except(scratch, divisor.span(), &["rax", "rdx"])?;
+                let t = register::sized(&t, width).as_bytes().to_vec();
+                self.append_text(format!("mov {}, {}\n", from_utf8(&t).unwrap(), Program::expression_data(divisor.clone(), width)?).as_ref());
+                release(scratch, divisor);
+                t
+            }
+        };
+        let saved: Vec<&str> = ["rax", "rdx"]
+            .iter()
+            .filter(|r| **r != register::family(dest) && !scratch.free.iter().any(|free| free == r.as_bytes()))
+            .cloned()
+            .collect();
+        for reg in &saved {
+            self.append_text(format!("push {}\n", reg).as_ref());
+        }
+        let reg = from_utf8(dest).unwrap();
+        let (quotient, remainder) = (register::sized(b"rax", width), register::sized(b"rdx", width));
+        if quotient != reg {
+            self.append_text(format!("mov {}, {}\n", quotient, reg).as_ref());
+        }
+        let signed = matches!(operator, Operator::SignedDivide | Operator::SignedModulus);
+        if signed {
+            // sign extends rax into rdx
+            let extend = match width {
+                Type::Uint16 => "cwd",
+                Type::Uint32 => "cdq",
+                _ => "cqo",
+            };
+            self.append_text(format!("{}\n", extend).as_ref());
+        } else {
+            // only as wide as the division, a word sized one can't touch the rest of rdx.
+            // a qword clears it through edx, which is shorter and zeroes the top half anyway
+            let clear = if width == Type::Uint64 { "edx" } else { remainder };
+            self.append_text(format!("xor {}, {}\n", clear, clear).as_ref());
+        }
+        let instruction = if signed { "idiv" } else { "div" };
+        self.append_text(format!("{} {}\n", instruction, from_utf8(&divisor).unwrap()).as_ref());
+        let result = if matches!(operator, Operator::Divide | Operator::SignedDivide) { quotient } else { remainder };
+        if result != reg {
+            self.append_text(format!("mov {}, {}\n", reg, result).as_ref());
+        }
+        for reg in saved.iter().rev() {
+            self.append_text(format!("pop {}\n", reg).as_ref());
+        }
+        release(scratch, Expression::Register { reg: divisor, span: Span::default() });
+        Ok(())
+    }
+
+    // a number or register holding the value of `expr` at `width`, computed into a scratch register if it has to be
+    fn compile_operand(&mut self, expr: &Expression, width: Type, scratch: &mut Scratch) -> Result<Expression, Diagnostic> {
+        match expr {
+            Expression::Number { value, span } => {
+                check_immediate(*value, width, *span)?;
+                // only qword operations sign extend their immediate
+                if width < Type::Uint64 || fits_imm32(*value) {
+                    return Ok(expr.clone());
+                }
+            }
+            Expression::Register { reg, span } if register::width(reg) >= width => {
+                return Ok(Expression::Register { reg: register::sized(reg, width).as_bytes().to_vec(), span: *span });
+            }
+            _ => {}
+        }
+        let t = register::sized(&self.take_scratch(scratch, expr.span())?, width).as_bytes().to_vec();
+        self.compile_into(expr, &t, scratch)?;
+        Ok(Expression::Register { reg: t, span: expr.span() })
+    }
+
+    // the widest register, sized load or array element in an expression
+    fn natural_width(&self, expr: &Expression) -> Option<Type> {
+        match expr {
+            Expression::Binary { left, right, .. } => self.natural_width(left).max(self.natural_width(right)),
+            Expression::Unary { operand, .. } => self.natural_width(operand),
+            Expression::Register { reg, .. } => Some(register::width(reg)),
+            Expression::Load { width: Some(width), .. } => Some(*width),
+            Expression::Load { address, .. } if address.element => address.symbol.as_ref().and_then(|array| self.element_type(array)),
+            _ => None,
+        }
+    }
+
+    // the constant an expression points into and where the pointer was made, if it points into one.
+    // copying a pointer, or adding to or subtracting from it, still points into the same constant
+    fn constant_pointer(&self, expression: &Expression, pointers: &Pointers, span: Span) -> Option<(Vec<u8>, Span)> {
+        match expression {
+            Expression::Variable { var, .. } if self.constants.contains(var) && self.local(var).is_none() => Some((var.clone(), span)),
+            Expression::Register { reg, .. } => pointers.get(register::family(reg)).cloned(),
+            Expression::Binary { left, right, operator: Operator::Add, .. } => {
+                self.constant_pointer(left, pointers, span).or_else(|| self.constant_pointer(right, pointers, span))
+            }
+            Expression::Binary { left, operator: Operator::Subtract, .. } => self.constant_pointer(left, pointers, span),
+            _ => None,
+        }
+    }
+
+    // stores can't go to a constant, whether it's indexed or pointed to by a register
+    fn check_writable(&self, address: &Address, pointers: &Pointers, span: Span) -> Result<(), Diagnostic> {
+        let read_only = |constant: &[u8]| Diagnostic::error(diagnostic::READ_ONLY_STORE, format!("cannot store to the constant `{}`", from_utf8(constant).unwrap()))
+            .with_primary(span, "writes to read only memory")
+            .with_note("constants live in `.rodata`, declare it with `let` to change it");
+        if let Some(symbol) = address.symbol.as_ref().filter(|s| self.constants.contains(*s) && self.local(s).is_none()) {
+            return Err(read_only(symbol));
+        }
+        let pointer = address.base.as_ref().and_then(|base| pointers.get(register::family(base)));
+        if let Some((constant, assigned)) = pointer {
+            return Err(read_only(constant)
+                .with_secondary(*assigned, format!("`{}` is pointed at from here", from_utf8(constant).unwrap())));
+        }
+        Ok(())
+    }
+
+    // scales an element address by the type of its array, which is also the width of the element.
+    // locals are found relative to rbp instead of by their name
+    fn resolve(&self, address: &Address, span: Span) -> Result<(Address, Option<Type>), Diagnostic> {
+        if !address.element {
+            return Ok((address.clone(), None));
+        }
+        let array = address.symbol.as_ref().unwrap();
+        let v_type = self.element_type(array).ok_or_else(|| {
+            Diagnostic::error(diagnostic::UNKNOWN_VARIABLE, format!("cannot find `{}`", from_utf8(array).unwrap()))
+                .with_primary(span, "indexes something that isn't declared")
+                .with_note("only variables declared with `let` or `const` can be indexed")
+        })?;
+        let size = v_type.size();
+        let mut address = address.clone();
+        address.element = false;
+        address.index = address.index.map(|(index, _)| (index, size));
+        let mut displacement = address.displacement.checked_mul(size as i64);
+        if let Some(local) = self.local(array) {
+            address.symbol = None;
+            address.base = Some(b"rbp".to_vec());
+            displacement = displacement.and_then(|d| d.checked_sub(local.offset as i64));
+        }
+        // the same limit as any other address, but only known once the index is scaled
+        address.displacement = displacement.filter(|d| (i32::MIN as i64..=i32::MAX as i64).contains(d)).ok_or_else(|| {
+            Diagnostic::error(diagnostic::INVALID_ADDRESS, "address displacement is too large")
+                .with_primary(span, format!("displacement doesn't fit in 32 bits once scaled by {}", size))
+        })?;
+        Ok((address, Some(v_type)))
+    }
+
+    // a qword register to hold an intermediate value, one is saved on the stack if none are free
+    fn take_scratch(&mut self, scratch: &mut Scratch, span: Span) -> Result<Vec<u8>, Diagnostic> {
+        self.take_scratch_except(scratch, span, &[])
+    }
+
+    fn take_scratch_except(&mut self, scratch: &mut Scratch, span: Span, except: &[&str]) -> Result<Vec<u8>, Diagnostic> {
+        let excepted = |r: &[u8]| except.iter().any(|e| e.as_bytes() == r);
+        if let Some(i) = scratch.free.iter().rposition(|r| !excepted(r)) {
+            return Ok(scratch.free.remove(i));
+        }
+        let out_of_registers = || Diagnostic::error(diagnostic::UNSUPPORTED_OPERATION, "not enough registers to compute this expression")
+            .with_primary(span, "needs a scratch register");
+        // pushing moves rsp, which would change the value being read
+        if scratch.used.iter().any(|r| r == b"rsp") {
+            return Err(out_of_registers().with_note("declare a free register with `scratch(...)` on the function"));
+        }
+        let reg = SAVE_ORDER
+            .iter()
+            .map(|r| r.as_bytes().to_vec())
+            // registers declared free are either in `free` or holding a value already
+            .find(|r| !scratch.used.contains(r) && !self.scratch.contains(r) && !scratch.saved.contains(r) && !excepted(r))
+            .ok_or_else(out_of_registers)?;
+        self.append_text(format!("push {}\n", from_utf8(&reg).unwrap()).as_ref());
+        scratch.saved.push(reg.clone());
+        Ok(reg)
+    }
+
+    // pops the registers saved for the statement, which leaves the flags alone
+    fn restore_scratch(&mut self, scratch: Scratch) {
+        for reg in scratch.saved.iter().rev() {
+            self.append_text(format!("pop {}\n", from_utf8(reg).unwrap()).as_ref());
+        }
+    }
+
+    // scratch registers for a statement that reads or writes `used`, which are all qword registers
+    fn scratch(&self, used: Vec<Vec<u8>>) -> Scratch {
+        // reversed so they're handed out in the order they were declared
+        let free = self.scratch.iter().rev().filter(|r| !used.contains(r)).cloned().collect();
+        Scratch { free, saved: vec![], used }
+    }
+
+    // folds the parts of an expression that are only numbers
+    fn fold(&self, expr: &Expression) -> Result<Expression, Diagnostic> {
+        Ok(match expr {
+            // the size of a local is known here, a global's is left to NASM
+            Expression::Variable { var, span } => match self.locals.iter().rev().find(|local| local_len(&local.name) == *var) {
+                Some(local) => Expression::Number { value: local.v_type.size() * local.count, span: *span },
+                None => expr.clone(),
+            },
+            Expression::Binary { left, right, operator, span } => {
+                let left = self.fold(left)?;
+                let right = self.fold(right)?;
+                match (&left, &right) {
+                    (Expression::Number { value: v1, .. }, Expression::Number { value: v2, .. }) => {
+                        // cant create asm code for 2 numbers together so just optimize
+                        let f = Program::operator_function(operator).map_err(|d| d.with_primary(*span, "between these numbers"))?;
+                        let value = f(*v1, *v2).ok_or_else(|| division_by_zero(right.span()))?;
+                        Expression::Number { value, span: *span }
                     }
-                    _ => panic!("operations between registers is not supported")
+                    _ => Expression::Binary { left: Box::new(left), right: Box::new(right), operator: operator.clone(), span: *span },
                 }
             }
-            Expression::Number { value } => expr_copy,
-            Expression::Register { reg } => expr_copy,
-            _ => panic!("i am sleep deprived")
-        }, instructions)
+            Expression::Unary { operand, operator, span } => match self.fold(operand)? {
+                Expression::Number { value, .. } => Expression::Number { value: Program::unary_function(operator)(value), span: *span },
+                operand => Expression::Unary { operand: Box::new(operand), operator: operator.clone(), span: *span },
+            },
+            _ => expr.clone(),
+        })
+    }
+
+    // numbers are 64 bits and wrap like registers do, only dividing by zero fails
+    fn operator_function(operator: &Operator) -> Result<Fold, Diagnostic> {
+        match operator {
+            Operator::Add => Ok(|x, y| Some(x.wrapping_add(y))),
+            Operator::Subtract => Ok(|x, y| Some(x.wrapping_sub(y))),
+            Operator::Multiply => Ok(|x, y| Some(x.wrapping_mul(y))),
+            Operator::Divide => Ok(|x, y| x.checked_div(y)),
+            Operator::Modulus => Ok(|x, y| x.checked_rem(y)),
+            Operator::SignedDivide => Ok(|x, y| (y != 0).then(|| (x as i64).wrapping_div(y as i64) as usize)),
+            Operator::SignedModulus => Ok(|x, y| (y != 0).then(|| (x as i64).wrapping_rem(y as i64) as usize)),
+            Operator::BitAnd => Ok(|x, y| Some(x & y)),
+            Operator::BitOr => Ok(|x, y| Some(x | y)),
+            Operator::BitXor => Ok(|x, y| Some(x ^ y)),
+            // the count is masked to 6 bits, same as the cpu does
+            Operator::ShiftLeft => Ok(|x, y| Some(x.wrapping_shl(y as u32 & 63))),
+            Operator::ShiftRight => Ok(|x, y| Some(x.wrapping_shr(y as u32 & 63))),
+            Operator::SignedShiftRight => Ok(|x, y| Some((x as i64).wrapping_shr(y as u32 & 63) as usize)),
+            _ => Err(unsupported(operator, "between two numbers")),
+        }
     }
 
-    fn operator_function(operator: Operator) -> (fn(usize, usize) -> usize) {
+    fn unary_function(operator: &Operator) -> fn(usize) -> usize {
         match operator {
-            Operator::Add => |x, y| x + y,
-            Operator::Multiply => |x, y| x * y,
-            _ => panic!("operator not valid")
+            Operator::BitNot => |x| !x,
+            Operator::Subtract => |x| x.wrapping_neg(),
+            _ => unreachable!("the parser only makes `~` and `-` unary"),
         }
     }
 
-    fn expression_data(data: Expression) -> String {
+    fn relation_function(relation: &Operator) -> fn(usize, usize) -> bool {
+        match relation {
+            Operator::Equal => |x, y| x == y,
+            Operator::NotEqual => |x, y| x != y,
+            Operator::Less => |x, y| x < y,
+            Operator::LessEqual => |x, y| x <= y,
+            Operator::Greater => |x, y| x > y,
+            Operator::GreaterEqual => |x, y| x >= y,
+            Operator::SignedLess => |x, y| (x as isize) < (y as isize),
+            Operator::SignedLessEqual => |x, y| (x as isize) <= (y as isize),
+            Operator::SignedGreater => |x, y| (x as isize) > (y as isize),
+            Operator::SignedGreaterEqual => |x, y| (x as isize) >= (y as isize),
+            _ => unreachable!("the parser only gives relations to comparisons"),
+        }
+    }
+
+    fn expression_data(data: Expression, width: Type) -> Result<String, Diagnostic> {
         match data {
-            Expression::Number { value } => value.to_string(),
-            Expression::Register { reg } => from_utf8(&reg).unwrap().to_string(),
-            _ => panic!("expression is not computable")
+            Expression::Number { value, .. } => Ok(immediate_data(value, width)),
+            Expression::Register { reg, .. } => Ok(from_utf8(&reg).unwrap().to_string()),
+            _ => Err(Diagnostic::error(diagnostic::UNSUPPORTED_OPERATION, "expression is not computable")
+                .with_primary(data.span(), "only registers and numbers can be used here")),
         }
     }
 
-    fn operator_reg_imm(operator: Operator, register: &Vec<u8>, imm: usize) -> Vec<u8> {
-        let reg = String::from_utf8(register.clone()).unwrap();
+    fn operator_reg_imm(operator: &Operator, reg: &[u8], imm: usize) -> Result<Vec<u8>, Diagnostic> {
+        let width = register::width(reg);
+        let reg = from_utf8(reg).unwrap();
+        let imm_data = immediate_data(imm, width);
         match operator {
             Operator::Add => {
-                format!("add {}, {}\n", reg, imm).into_bytes()
+                Ok(format!("add {}, {}\n", reg, imm_data).into_bytes())
             }
+            // lea can only scale by 1, 2, 4 or 8, plus the register itself for 3, 5 and 9
+            Operator::Multiply if width == Type::Uint64 && matches!(imm, 1..=5 | 8 | 9) => {
+                Ok(format!("lea {}, [{}*{}]\n", reg, reg, imm).into_bytes())
+            },
+            // there's no two operand form of imul for bytes
+            Operator::Multiply if width == Type::Uint8 => Err(unsupported(operator, "on byte registers")),
             Operator::Multiply => {
-                format!("lea {}, [{}*{}]\n", reg, reg, imm).into_bytes()
+                Ok(format!("imul {}, {}, {}\n", reg, reg, imm_data).into_bytes())
             },
             Operator::Subtract => {
-                format!("sub {}, {}\n", reg, imm).into_bytes()
+                Ok(format!("sub {}, {}\n", reg, imm_data).into_bytes())
             }
-            _ => panic!("cant gen code for register and imm")
+            Operator::BitAnd | Operator::BitOr | Operator::BitXor => {
+                Ok(format!("{} {}, {}\n", bitwise_instruction(operator), reg, imm_data).into_bytes())
+            }
+            Operator::ShiftLeft | Operator::ShiftRight | Operator::SignedShiftRight => {
+                Ok(format!("{} {}, {}\n", shift_instruction(operator), reg, imm & 63).into_bytes())
+            }
+            _ => Err(unsupported(operator, "between a register and a number")),
         }
     }
 
-    fn operator_reg_reg(operator: Operator, r1: &Vec<u8>, r2: &Vec<u8>) -> Vec<u8> {
-        let reg1 = String::from_utf8(r1.clone()).unwrap();
-        let reg2 = String::from_utf8(r2.clone()).unwrap();
+    fn operator_reg_reg(operator: &Operator, r1: &[u8], r2: &[u8]) -> Result<Vec<u8>, Diagnostic> {
+        let reg1 = from_utf8(r1).unwrap();
+        let reg2 = from_utf8(r2).unwrap();
         match operator {
             Operator::Add => {
-                format!("add {}, {}\n", reg1, reg2).into_bytes()
+                Ok(format!("add {}, {}\n", reg1, reg2).into_bytes())
             },
             Operator::Subtract => {
-                format!("sub {}, {}\n", reg1, reg2).into_bytes()
+                Ok(format!("sub {}, {}\n", reg1, reg2).into_bytes())
             }
-            _ => panic!("this operation is not supported from register to register")
+            Operator::Multiply if register::width(r1) == Type::Uint8 => Err(unsupported(operator, "on byte registers")),
+            Operator::Multiply => {
+                Ok(format!("imul {}, {}\n", reg1, reg2).into_bytes())
+            }
+            Operator::BitAnd | Operator::BitOr | Operator::BitXor => {
+                Ok(format!("{} {}, {}\n", bitwise_instruction(operator), reg1, reg2).into_bytes())
+            }
+            _ => Err(unsupported(operator, "from register to register")),
+        }
+    }
+
+    fn operator_reg(operator: &Operator, register: &[u8]) -> Result<Vec<u8>, Diagnostic> {
+        let reg = from_utf8(register).unwrap();
+        match operator {
+            Operator::BitNot => Ok(format!("not {}\n", reg).into_bytes()),
+            Operator::Subtract => Ok(format!("neg {}\n", reg).into_bytes()),
+            _ => Err(unsupported(operator, "on a register")),
         }
     }
 
-    // fn is_atom(expr: Expression) -> bool {
-    //     match expr {
-    //         Expression::Register | Expression::SizeOf | Expression::Number => true,
-    //         _ => false
-    //     }
-    // }
+    fn new_label(&mut self) -> String {
+        let label = format!("._{}_", self.label_counter);
+        self.label_counter += 1;
+        label
+    }
 
     fn append_text(&mut self, text: &[u8]) {
         self.text.extend_from_slice(text);
@@ -298,10 +1198,605 @@ impl Program {
     }
 
     fn append_data(&mut self, text: &[u8]) {
+        self.data.extend_from_slice(text);
+    }
+
+    fn append_ro_data(&mut self, text: &[u8]) {
         self.ro_data.extend_from_slice(text);
     }
 
     fn append_bss(&mut self, text: &[u8]) {
         self.bss.extend_from_slice(text);
     }
-}
\ No newline at end of file
+}
+
+// the jump taken when `relation` is `when`, e.g. jb for a true `<` or jae for a false one
+fn jump(relation: &Operator, when: bool) -> &'static str {
+    match (relation, when) {
+        (Operator::Equal, true) | (Operator::NotEqual, false) => "je",
+        (Operator::Equal, false) | (Operator::NotEqual, true) => "jne",
+        (Operator::Less, true) | (Operator::GreaterEqual, false) => "jb",
+        (Operator::Less, false) | (Operator::GreaterEqual, true) => "jae",
+        (Operator::LessEqual, true) | (Operator::Greater, false) => "jbe",
+        (Operator::LessEqual, false) | (Operator::Greater, true) => "ja",
+        (Operator::SignedLess, true) | (Operator::SignedGreaterEqual, false) => "jl",
+        (Operator::SignedLess, false) | (Operator::SignedGreaterEqual, true) => "jge",
+        (Operator::SignedLessEqual, true) | (Operator::SignedGreater, false) => "jle",
+        (Operator::SignedLessEqual, false) | (Operator::SignedGreater, true) => "jg",
+        _ => unreachable!("the parser only gives relations to comparisons"),
+    }
+}
+
+// the relation with its operands swapped, `a < b` is `b > a`
+fn mirror(relation: &Operator) -> Operator {
+    match relation {
+        Operator::Less => Operator::Greater,
+        Operator::LessEqual => Operator::GreaterEqual,
+        Operator::Greater => Operator::Less,
+        Operator::GreaterEqual => Operator::LessEqual,
+        Operator::SignedLess => Operator::SignedGreater,
+        Operator::SignedLessEqual => Operator::SignedGreaterEqual,
+        Operator::SignedGreater => Operator::SignedLess,
+        Operator::SignedGreaterEqual => Operator::SignedLessEqual,
+        _ => relation.clone(),
+    }
+}
+
+// computes an operator at compile time, None when dividing by zero
+type Fold = fn(usize, usize) -> Option<usize>;
+
+// registers that get saved on the stack to use as scratch when none are declared free, in the order they're tried
+const SAVE_ORDER: [&str; 14] = ["r11", "r10", "r9", "r8", "rcx", "rdx", "rsi", "rdi", "rbx", "rax", "r12", "r13", "r14", "r15"];
+
+// registers a statement can use to hold intermediate values
+struct Scratch {
+    // declared free by the function and not holding anything yet
+    free: Vec<Vec<u8>>,
+    // pushed in this order, and popped once the statement is done
+    saved: Vec<Vec<u8>>,
+    // read or written by the statement itself, so never used as scratch
+    used: Vec<Vec<u8>>,
+}
+
+// hands a scratch register back once its value has been used up
+fn release(scratch: &mut Scratch, operand: Expression) {
+    if let Expression::Register { reg, .. } = operand {
+        let reg = register::family(&reg).as_bytes().to_vec();
+        if !scratch.used.contains(&reg) {
+            scratch.free.push(reg);
+        }
+    }
+}
+
+// the type of the elements of a literal when it isn't given one
+fn literal_type(literal: &Literal) -> Type {
+    match literal {
+        Literal::String(_) => Type::Uint8,
+        Literal::Array(_, v_type, _) => *v_type,
+        Literal::Int(_) => Type::Uint64,
+    }
+}
+
+// the type, count and values of `name` initialized to `literal`, any elements past the values are zero
+fn literal_layout(name: &[u8], literal: &Literal, description: Option<(Type, usize)>, span: Span) -> Result<(Type, usize, Vec<usize>), Diagnostic> {
+    let name = from_utf8(name).unwrap();
+    let v_type = description.map_or(literal_type(literal), |(t, _)| t);
+    let values = match literal {
+        Literal::String(s) => {
+            if v_type != Type::Uint8 {
+                return Err(Diagnostic::error(diagnostic::MISMATCHED_TYPE, format!("`{}` is a string but was given a {} type", name, v_type))
+                    .with_primary(span, "strings are made of bytes")
+                    .with_note(format!("use `[byte, {}]` instead", description.map_or(0, |(_, count)| count).max(s.len()))));
+            }
+            s.iter().map(|&c| c as usize).collect()
+        }
+        Literal::Int(value) => {
+            check_immediate(*value, v_type, span)?;
+            vec![*value]
+        }
+        // the parser already sized and checked arrays against their description
+        Literal::Array(values, _, _) => values.iter().map(|value| match value {
+            Literal::Int(value) => *value,
+            _ => unreachable!("arrays only hold numbers"),
+        }).collect(),
+    };
+    let count = match (literal, description) {
+        (Literal::Array(_, _, count), _) => *count,
+        (_, Some((_, count))) => count,
+        (_, None) => values.len(),
+    };
+    if values.len() > count {
+        return Err(Diagnostic::error(diagnostic::MISMATCHED_TYPE, format!("`{}` is {} long but only has room for {}", name, values.len(), count))
+            .with_primary(span, format!("needs a count of at least {}", values.len())));
+    }
+    Ok((v_type, count, values))
+}
+
+// defines `name` as `literal`, padded with zeros out to the count of its description
+fn literal_data(name: &[u8], literal: &Literal, description: Option<(Type, usize)>, span: Span) -> Result<String, Diagnostic> {
+    let (v_type, count, values) = literal_layout(name, literal, description, span)?;
+    let name = from_utf8(name).unwrap();
+    let directive = define_data(v_type);
+    // written as numbers so escaped bytes come out exactly
+    let mut data = if values.is_empty() {
+        format!("{}:\n", name)
+    } else {
+        let values: Vec<String> = values.iter().map(|value| immediate_data(*value, v_type)).collect();
+        format!("{}: {} {}\n", name, directive, values.join(", "))
+    };
+    if count > values.len() {
+        data.push_str(&format!("times {} {} 0\n", count - values.len(), directive));
+    }
+    data.push_str(&format!("{}_LEN equ $ - {}\n", name.to_uppercase(), name));
+    Ok(data)
+}
+
+fn missing_type(name: &[u8], span: Span) -> Diagnostic {
+    Diagnostic::error(diagnostic::MISSING_TYPE, format!("`{}` needs a type", from_utf8(name).unwrap()))
+        .with_primary(span, "buffer declared without a type")
+        .with_note("uninitialized buffers are declared like `let buffer: [byte, 32]`")
+}
+
+// the name `sizeof(name)` is parsed into
+fn local_len(name: &[u8]) -> Vec<u8> {
+    let mut len = name.to_ascii_uppercase();
+    len.extend_from_slice(b"_LEN");
+    len
+}
+
+// where a local of `count` elements ends when the one before it ends at `offset`, aligned to its type
+fn allocate(offset: usize, v_type: Type, count: usize) -> usize {
+    let size = v_type.size();
+    (offset + size * count).next_multiple_of(size)
+}
+
+// the stack every `let` in a function takes, walked in the same order they're compiled in
+fn frame_size(function: &ScopeImpl) -> usize {
+    let mut offset = 0;
+    function.each_statement(&mut |statement| {
+        if let Statement::NewLet { name: Identifier::Variable(name), literal, v_type, span } = statement {
+            let layout = match literal {
+                Some(literal) => literal_layout(name, literal, *v_type, *span).ok().map(|(t, count, _)| (t, count)),
+                None => *v_type,
+            };
+            if let Some((v_type, count)) = layout {
+                offset = allocate(offset, v_type, count);
+            }
+        }
+    });
+    offset
+}
+
+// where an expression uses an address, if it does
+fn address_in(expr: &Expression) -> Option<Span> {
+    match expr {
+        Expression::Binary { left, right, .. } => address_in(left).or_else(|| address_in(right)),
+        Expression::Unary { operand, .. } => address_in(operand),
+        Expression::Variable { span, .. } => Some(*span),
+        _ => None,
+    }
+}
+
+// pointers that hold wherever two paths meet, a path that's None never gets there
+fn join(left: Option<Pointers>, right: Option<Pointers>) -> Option<Pointers> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left.into_iter().filter(|(r, (constant, _))| right.get(r).is_some_and(|(c, _)| c == constant)).collect()),
+        (left, None) => left,
+        (None, right) => right,
+    }
+}
+
+fn makes_calls(function: &ScopeImpl) -> bool {
+    let mut calls = false;
+    function.each_statement(&mut |statement| calls |= matches!(statement, Statement::Call { .. }));
+    calls
+}
+
+// the directive that defines initialized data of a type
+fn define_data(v_type: Type) -> &'static str {
+    match v_type {
+        Type::Uint8 => "db",
+        Type::Uint16 => "dw",
+        Type::Uint32 => "dd",
+        Type::Uint64 => "dq",
+    }
+}
+
+// `rsi + rcx*8 - 16`
+fn address_data(address: &Address) -> String {
+    let mut parts: Vec<String> = address.symbol.iter().chain(address.base.iter()).map(|part| from_utf8(part).unwrap().to_string()).collect();
+    match &address.index {
+        Some((index, 1)) => parts.push(from_utf8(index).unwrap().to_string()),
+        Some((index, scale)) => parts.push(format!("{}*{}", from_utf8(index).unwrap(), scale)),
+        None => {}
+    }
+    let mut data = parts.join(" + ");
+    if parts.is_empty() {
+        data = address.displacement.to_string();
+    } else if address.displacement > 0 {
+        data.push_str(&format!(" + {}", address.displacement));
+    } else if address.displacement < 0 {
+        data.push_str(&format!(" - {}", address.displacement.unsigned_abs()));
+    }
+    data
+}
+
+fn registers_read(expr: &Expression, registers: &mut Vec<Vec<u8>>) {
+    match expr {
+        Expression::Binary { left, right, .. } => {
+            registers_read(left, registers);
+            registers_read(right, registers);
+        }
+        Expression::Unary { operand, .. } => registers_read(operand, registers),
+        Expression::Register { reg, .. } => registers.push(register::family(reg).as_bytes().to_vec()),
+        Expression::Load { address, .. } => registers.extend(address.registers().cloned()),
+        _ => {}
+    }
+}
+
+// whether any part of `reg` is read by the expression
+fn reads(expr: &Expression, reg: &[u8]) -> bool {
+    let mut registers = vec![];
+    registers_read(expr, &mut registers);
+    registers.iter().any(|r| r == register::family(reg).as_bytes())
+}
+
+
+fn divides(operator: &Operator) -> bool {
+    matches!(operator, Operator::Divide | Operator::Modulus | Operator::SignedDivide | Operator::SignedModulus)
+}
+
+fn shifts(operator: &Operator) -> bool {
+    matches!(operator, Operator::ShiftLeft | Operator::ShiftRight | Operator::SignedShiftRight)
+}
+
+fn commutative(operator: &Operator) -> bool {
+    matches!(operator, Operator::Add | Operator::Multiply | Operator::BitAnd | Operator::BitOr | Operator::BitXor)
+}
+
+fn bitwise_instruction(operator: &Operator) -> &'static str {
+    match operator {
+        Operator::BitAnd => "and",
+        Operator::BitOr => "or",
+        Operator::BitXor => "xor",
+        _ => unreachable!("only called with bitwise operators"),
+    }
+}
+
+fn shift_instruction(operator: &Operator) -> &'static str {
+    match operator {
+        Operator::ShiftLeft => "shl",
+        Operator::ShiftRight => "shr",
+        Operator::SignedShiftRight => "sar",
+        _ => unreachable!("only called with shifts"),
+    }
+}
+
+// most instructions sign extend a 32 bit immediate, anything else has to go through a register
+fn fits_imm32(value: usize) -> bool {
+    (value as i64) >= i32::MIN as i64 && (value as i64) <= i32::MAX as i64
+}
+
+fn check_immediate(value: usize, width: Type, span: Span) -> Result<(), Diagnostic> {
+    if width.fits(value) {
+        Ok(())
+    } else {
+        Err(Diagnostic::error(diagnostic::IMMEDIATE_OUT_OF_RANGE, format!("`{}` doesn't fit in a {}", value, width))
+            .with_primary(span, format!("needs more than {} bits", width.size() * 8)))
+    }
+}
+
+// qwords are written signed so NASM sees `-1` instead of a number that doesn't fit in 64 bits signed,
+// anything narrower is cut down to its width
+fn immediate_data(value: usize, width: Type) -> String {
+    match width {
+        Type::Uint8 => (value as u8).to_string(),
+        Type::Uint16 => (value as u16).to_string(),
+        Type::Uint32 => (value as u32).to_string(),
+        Type::Uint64 => (value as i64).to_string(),
+    }
+}
+
+fn division_by_zero(span: Span) -> Diagnostic {
+    Diagnostic::error(diagnostic::DIVISION_BY_ZERO, "attempt to divide by zero")
+        .with_primary(span, "this is zero")
+}
+
+fn unsupported(operator: &Operator, operands: &str) -> Diagnostic {
+    Diagnostic::error(diagnostic::UNSUPPORTED_OPERATION, format!("`{}` is not supported {}", operator, operands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::stream::IStream;
+    use crate::tokenizer::Tokenizer;
+
+    // compiles `source` and gives back the header and text section, or the code of the first error
+    fn compile(source: &str) -> Result<String, &'static str> {
+        let text = source.as_bytes().to_vec();
+        let mut is = IStream::new(&text, 0);
+        let mut tokenizer = Tokenizer::new(&mut is);
+        if let Some(d) = tokenizer.take_diagnostics().first() {
+            return Err(d.code);
+        }
+        let parser = Parser::new(tokenizer.get_tokens()).map_err(|d| d[0].code)?;
+        let mut program = Program::new();
+        program.compile(parser.scope_stack).map_err(|d| d[0].code)?;
+        Ok(String::from_utf8([program.header, program.text].concat()).unwrap())
+    }
+
+    // compiles `source` and gives back its data, read only data and bss sections
+    fn data(source: &str) -> String {
+        let text = source.as_bytes().to_vec();
+        let mut is = IStream::new(&text, 0);
+        let tokenizer = Tokenizer::new(&mut is);
+        let parser = Parser::new(tokenizer.get_tokens()).unwrap_or_else(|_| panic!("`{}` doesn't parse", source));
+        let mut program = Program::new();
+        program.compile(parser.scope_stack).unwrap_or_else(|_| panic!("`{}` doesn't compile", source));
+        String::from_utf8([program.data, program.ro_data, program.bss].concat()).unwrap()
+    }
+
+    #[test]
+    fn calls_are_made_with_an_aligned_stack() {
+        assert_eq!(compile("fn a {\ncall(puts, 1)\n}").unwrap(), "extern puts\nsection .text\na:\nsub rsp, 8\nmov rdi, 1\ncall puts\nadd rsp, 8\nret\n\n");
+        assert_eq!(compile("fn a uses(rbx) {\ncall(puts, 1)\n}").unwrap(), "extern puts\nsection .text\na:\npush rbx\nmov rdi, 1\ncall puts\npop rbx\nret\n\n");
+        assert_eq!(compile("fn a {\n$rax = 1\n}").unwrap(), "section .text\na:\nmov rax, 1\nret\n\n");
+    }
+
+    #[test]
+    fn functions_defined_elsewhere_are_extern() {
+        let text = compile("extern fn a {\ncall(puts, 1)\ncall(puts, 2)\ncall(b)\n}\nfn b {\n}").unwrap();
+        assert!(text.starts_with("global a\nextern puts\nsection .text\n"));
+        assert!(!text.contains("extern b"));
+    }
+
+    #[test]
+    fn unsigned_division_only_clears_its_width_of_rdx() {
+        assert_eq!(
+            compile("fn f {\n$dx = $r8w / 7\n}").unwrap(),
+            "section .text\nf:\nmov dx, r8w\npush r11\nmov r11w, 7\npush rax\nmov ax, dx\nxor dx, dx\ndiv r11w\nmov dx, ax\npop rax\npop r11\nret\n\n",
+        );
+        assert!(compile("fn f {\n$rcx = $rcx / $rbx\n}").unwrap().contains("xor edx, edx\ndiv rbx\n"));
+    }
+
+    #[test]
+    fn scaled_indices_have_to_fit_in_32_bits() {
+        let text = "let arr: [qword, 4]\nfn f {\n";
+        assert_eq!(compile(&format!("{}$rax = arr[0x100000000]\n}}", text)), Err(diagnostic::INVALID_ADDRESS));
+        assert_eq!(compile(&format!("{}arr[0x10000000] = 1\n}}", text)), Err(diagnostic::INVALID_ADDRESS));
+        assert!(compile(&format!("{}$rax = arr[0xfffffff - 1]\n}}", text)).unwrap().contains("[arr + 2147483632]"));
+    }
+
+    #[test]
+    fn addresses_need_a_wide_store() {
+        let text = "let buf: [byte, 4]\nfn f {\n";
+        assert_eq!(compile(&format!("{}$*rdi = buf\n}}", text)), Err(diagnostic::UNSUPPORTED_OPERATION));
+        assert_eq!(compile(&format!("{}$*rdi:word = buf + 1\n}}", text)), Err(diagnostic::UNSUPPORTED_OPERATION));
+        assert!(compile(&format!("{}$*rdi:qword = buf\n}}", text)).is_ok());
+    }
+
+    #[test]
+    fn constant_pointers_follow_branches() {
+        let text = "const msg = \"hi\"\nlet buf: [byte, 4]\nfn f {\n$rsi = buf\n";
+        assert!(compile(&format!("{}if $rax == 0 {{\n$rsi = msg\n$rdx = 2\n}} else {{\n$*rsi = 0\n}}\n}}", text)).is_ok());
+        assert!(compile(&format!("{}if $rax == 0 {{\n$rsi = msg\n}} else if $rax == 1 {{\n$*rsi = 0\n}}\n}}", text)).is_ok());
+        assert!(compile(&format!("{}if $rax == 0 {{\n$rsi = msg\n}}\n$*rsi = 0\n}}", text)).is_ok());
+        assert_eq!(compile(&format!("{}if $rax == 0 {{\n$rsi = msg\n}} else {{\n$rsi = msg + 1\n}}\n$*rsi = 0\n}}", text)), Err(diagnostic::READ_ONLY_STORE));
+    }
+
+    #[test]
+    fn constant_pointers_follow_loops() {
+        let text = "const msg = \"hi\"\nlet buf: [byte, 4]\nfn f {\n$rsi = buf\n";
+        // from the second pass on
+        assert_eq!(compile(&format!("{}while $rax != 0 {{\n$*rsi = 0\n$rsi = msg\n}}\n}}", text)), Err(diagnostic::READ_ONLY_STORE));
+        assert_eq!(compile(&format!("{}$rsi = msg\nloop {{\n$*rsi = 0\n}}\n}}", text)), Err(diagnostic::READ_ONLY_STORE));
+        assert!(compile(&format!("{}loop {{\n$*rsi = 0\n$rsi = msg\nbreak\n}}\n}}", text)).is_ok());
+        assert!(compile(&format!("{}$rsi = msg\nwhile $rax != 0 {{\n$rsi = buf\n}}\n$*rsi = 0\n}}", text)).is_ok());
+        assert!(compile(&format!("{}loop {{\n$rsi = msg\nbreak\n}}\n$*rsi = 0\n}}", text)).is_err());
+    }
+
+    #[test]
+    fn stores_through_constant_pointers_are_errors() {
+        let text = "const my_text = \"abcdefghij\"\nfn f {\n";
+        assert_eq!(compile(&format!("{}$rsi = my_text\n$*rsi = 0\n}}", text)), Err(diagnostic::READ_ONLY_STORE));
+        assert_eq!(compile(&format!("{}$rsi = my_text + 8\n$*rsi = 0\n}}", text)), Err(diagnostic::READ_ONLY_STORE));
+        assert_eq!(compile(&format!("{}$rsi = 8 + my_text\n$*rsi = 0\n}}", text)), Err(diagnostic::READ_ONLY_STORE));
+        assert_eq!(compile(&format!("{}$rsi = my_text\n$rsi = $rsi + 1\n$rdi = $rsi - 1\n$*rdi = 0\n}}", text)), Err(diagnostic::READ_ONLY_STORE));
+        assert!(compile(&format!("{}$rsi = my_text\n$rsi = 8 - $rsi\n$*rsi = 0\n}}", text)).is_ok());
+        assert!(compile(&format!("{}$rsi = my_text\n$rsi = 0\n$*rsi = 0\n}}", text)).is_ok());
+    }
+
+    #[test]
+    fn loops_jump_to_their_top_and_end() {
+        assert_eq!(
+            compile("fn f {\nwhile $rax != 0 {\nif $rax == 1 {\ncontinue\n}\n$rax = $rax - 1\n}\n}").unwrap(),
+            "section .text\nf:\n._0_:\ncmp rax, 0\nje ._1_\ncmp rax, 1\njne ._2_\njmp ._0_\n._2_:\nsub rax, 1\njmp ._0_\n._1_:\nret\n\n",
+        );
+        assert_eq!(
+            compile("fn f {\nloop {\nif $rax == 0 {\nbreak\n}\n}\n}").unwrap(),
+            "section .text\nf:\n._0_:\ncmp rax, 0\njne ._2_\njmp ._1_\n._2_:\njmp ._0_\n._1_:\nret\n\n",
+        );
+    }
+
+    #[test]
+    fn else_if_chains_share_an_end() {
+        assert_eq!(
+            compile("fn f {\nif $rax == 0 {\n$rbx = 1\n} else if $rax == 1 {\n$rbx = 2\n} else {\n$rbx = 3\n}\n}").unwrap(),
+            "section .text\nf:\ncmp rax, 0\njne ._0_\nmov rbx, 1\njmp ._1_\n._0_:\ncmp rax, 1\njne ._2_\nmov rbx, 2\njmp ._1_\n._2_:\nmov rbx, 3\n._1_:\nret\n\n",
+        );
+        // without a final else, the last skip label and the end are both placed after it
+        assert_eq!(
+            compile("fn f {\nif $rax == 0 {\n$rbx = 1\n} else if $rax == 1 {\n$rbx = 2\n}\n}").unwrap(),
+            "section .text\nf:\ncmp rax, 0\njne ._0_\nmov rbx, 1\njmp ._1_\n._0_:\ncmp rax, 1\njne ._2_\nmov rbx, 2\n._2_:\n._1_:\nret\n\n",
+        );
+    }
+
+    #[test]
+    fn relations_pick_signed_or_unsigned_jumps() {
+        // a constant on the left is swapped to the right, so the relation is mirrored
+        assert_eq!(
+            compile("fn f {\nif $rax <s $rbx {\n$rcx = 1\n}\nif $rax < $rbx {\n$rcx = 2\n}\nif 5 <s $rax {\n$rcx = 3\n}\nif 5 <= $rax {\n$rcx = 4\n}\n}").unwrap(),
+            "section .text\nf:\ncmp rax, rbx\njge ._0_\nmov rcx, 1\n._0_:\ncmp rax, rbx\njae ._1_\nmov rcx, 2\n._1_:\ncmp rax, 5\njle ._2_\nmov rcx, 3\n._2_:\ncmp rax, 5\njb ._3_\nmov rcx, 4\n._3_:\nret\n\n",
+        );
+    }
+
+    #[test]
+    fn conditions_short_circuit() {
+        let body = |condition: &str| {
+            let asm = compile(&format!("fn f {{\nif {} {{\n$rcx = 1\n}}\n}}", condition)).unwrap();
+            asm.trim_start_matches("section .text\nf:\n").trim_end().to_string()
+        };
+        assert_eq!(
+            body("$rax == 1 && $rbx == 2"),
+            "cmp rax, 1\njne ._0_\ncmp rbx, 2\njne ._0_\nmov rcx, 1\n._0_:\nret",
+        );
+        assert_eq!(
+            body("$rax == 1 || $rbx == 2"),
+            "cmp rax, 1\nje ._1_\ncmp rbx, 2\njne ._0_\n._1_:\nmov rcx, 1\n._0_:\nret",
+        );
+        assert_eq!(
+            body("!($rax == 1 || $rbx == 2) && $rcx >s 3"),
+            "cmp rax, 1\nje ._0_\ncmp rbx, 2\nje ._0_\ncmp rcx, 3\njle ._0_\nmov rcx, 1\n._0_:\nret",
+        );
+    }
+
+    #[test]
+    fn division_goes_through_rdx_rax() {
+        let body = |statement: &str| {
+            let asm = compile(&format!("fn f {{\n{}\n}}", statement)).unwrap();
+            asm.trim_start_matches("section .text\nf:\n").trim_end().to_string()
+        };
+        assert_eq!(
+            body("$rax = $rbx / $rcx"),
+            "mov rax, rbx\npush rdx\nxor edx, edx\ndiv rcx\npop rdx\nret",
+        );
+        // rdx as divisor is moved out of the way, rax is kept since it isn't the destination
+        assert_eq!(
+            body("$rdx = $rax % $rdx"),
+            "push r11\nmov r11, rdx\nmov rdx, rax\npush rax\nmov rax, rdx\nxor edx, edx\ndiv r11\npop rax\npop r11\nret",
+        );
+        assert_eq!(
+            body("$rcx = $rax /s $rdx"),
+            "mov rcx, rax\npush r11\nmov r11, rdx\npush rax\npush rdx\nmov rax, rcx\ncqo\nidiv r11\nmov rcx, rax\npop rdx\npop rax\npop r11\nret",
+        );
+        assert_eq!(
+            body("$eax = $ebx %s 10"),
+            "mov eax, ebx\npush r11\nmov r11d, 10\npush rdx\ncdq\nidiv r11d\nmov eax, edx\npop rdx\npop r11\nret",
+        );
+    }
+
+    #[test]
+    fn constant_arrays_are_padded_out_to_their_count() {
+        assert_eq!(
+            data("const table: [qword, 4] = [1, 2, 3, 4]"),
+            "section .data\nsection .rodata\ntable: dq 1, 2, 3, 4\nTABLE_LEN equ $ - table\nsection .bss\n",
+        );
+        assert_eq!(
+            data("const bytes: [byte, 6] = [1, 2]"),
+            "section .data\nsection .rodata\nbytes: db 1, 2\ntimes 4 db 0\nBYTES_LEN equ $ - bytes\nsection .bss\n",
+        );
+        assert_eq!(
+            data("const w: [word, 3] = [0xffff]"),
+            "section .data\nsection .rodata\nw: dw 65535\ntimes 2 dw 0\nW_LEN equ $ - w\nsection .bss\n",
+        );
+    }
+
+    #[test]
+    fn initialized_lets_are_writable_data() {
+        assert_eq!(
+            data("let counter = 5"),
+            "section .data\ncounter: dq 5\nCOUNTER_LEN equ $ - counter\nsection .rodata\nsection .bss\n",
+        );
+        assert_eq!(
+            data("let buf: [dword, 4] = 7"),
+            "section .data\nbuf: dd 7\ntimes 3 dd 0\nBUF_LEN equ $ - buf\nsection .rodata\nsection .bss\n",
+        );
+        assert_eq!(
+            data("let s: [byte, 8] = \"hi\""),
+            "section .data\ns: db 104, 105\ntimes 6 db 0\nS_LEN equ $ - s\nsection .rodata\nsection .bss\n",
+        );
+        // without a value it's only reserved
+        assert_eq!(
+            data("let b: [byte, 4]"),
+            "section .data\nsection .rodata\nsection .bss\nb: resb 4\nB_LEN equ $ - b\n",
+        );
+    }
+
+    #[test]
+    fn strings_are_decoded_and_c_strings_end_in_nul() {
+        assert_eq!(
+            data("const s = \"a\\tb\\n\\0\\\\\\\"\\x41\""),
+            "section .data\nsection .rodata\ns: db 97, 9, 98, 10, 0, 92, 34, 65\nS_LEN equ $ - s\nsection .bss\n",
+        );
+        assert_eq!(
+            data("const c = c\"hi\""),
+            "section .data\nsection .rodata\nc: db 104, 105, 0\nC_LEN equ $ - c\nsection .bss\n",
+        );
+    }
+
+    #[test]
+    fn arguments_are_moved_without_overwriting_each_other() {
+        let body = |statement: &str| {
+            let asm = compile(&format!("fn f {{\n{}\n}}", statement)).unwrap();
+            asm.trim_start_matches("extern puts\nsection .text\nf:\nsub rsp, 8\n").trim_end_matches("add rsp, 8\nret\n\n").to_string()
+        };
+        // a swap and a rotation go through r11
+        assert_eq!(body("call(puts, $rsi, $rdi)"), "push r11\nmov r11, rsi\nmov rsi, rdi\nmov rdi, r11\npop r11\ncall puts\n");
+        assert_eq!(body("call(puts, $rsi, $rdx, $rdi)"), "push r11\nmov r11, rsi\nmov rsi, rdx\nmov rdx, rdi\nmov rdi, r11\npop r11\ncall puts\n");
+        // otherwise a register is only written once nothing still needs it
+        assert_eq!(body("call(puts, $rsi, 1, $rdi + 2)"), "mov rdx, rdi\nadd rdx, 2\nmov rdi, rsi\nmov rsi, 1\ncall puts\n");
+    }
+
+    #[test]
+    fn calls_need_every_parameter() {
+        assert_eq!(compile("fn g(a: reg, b: dword) {\n}\nfn f {\ncall(g, 1)\n}"), Err(diagnostic::WRONG_ARGUMENT_COUNT));
+        assert_eq!(compile("fn g(a: reg, b: dword) {\n}\nfn f {\ncall(g, 1, 2, 3)\n}"), Err(diagnostic::WRONG_ARGUMENT_COUNT));
+        assert!(compile("fn g(a: reg, b: dword) {\n}\nfn f {\ncall(g, 1, 2)\n}").is_ok());
+    }
+
+    #[test]
+    fn frames_keep_calls_aligned() {
+        assert_eq!(
+            compile("fn f {\nlet a: [qword, 1]\ncall(puts)\n}").unwrap(),
+            "extern puts\nsection .text\nf:\npush rbp\nmov rbp, rsp\nsub rsp, 16\ncall puts\nmov rsp, rbp\npop rbp\nret\n\n",
+        );
+        // a saved register shifts the stack by 8, which the frame makes up for
+        assert_eq!(
+            compile("fn f uses(rbx) {\nlet a: [qword, 1]\ncall(puts)\n}").unwrap(),
+            "extern puts\nsection .text\nf:\npush rbx\npush rbp\nmov rbp, rsp\nsub rsp, 24\ncall puts\nmov rsp, rbp\npop rbp\npop rbx\nret\n\n",
+        );
+    }
+
+    #[test]
+    fn returns_jump_to_the_epilogue() {
+        assert_eq!(
+            compile("fn f {\nif $rax == 0 {\nreturn 1\n}\nreturn\n}").unwrap(),
+            "section .text\nf:\ncmp rax, 0\njne ._0_\nmov rax, 1\njmp ._1_\n._0_:\n._1_:\nret\n\n",
+        );
+        // the frame and saved registers are torn down once, after the label every return jumps to
+        assert_eq!(
+            compile("fn f uses(rbx) {\nlet a: [byte, 3]\nif $rax == 0 {\nreturn\n}\n$rbx = 1\n}").unwrap(),
+            "section .text\nf:\npush rbx\npush rbp\nmov rbp, rsp\nsub rsp, 24\ncmp rax, 0\njne ._0_\njmp ._1_\n._0_:\nmov rbx, 1\n._1_:\nmov rsp, rbp\npop rbp\npop rbx\nret\n\n",
+        );
+    }
+
+    #[test]
+    fn writing_a_preserved_register_warns() {
+        let warnings = |source: &str| {
+            let text = source.as_bytes().to_vec();
+            let mut is = IStream::new(&text, 0);
+            let tokenizer = Tokenizer::new(&mut is);
+            let parser = Parser::new(tokenizer.get_tokens()).unwrap();
+            let mut program = Program::new();
+            program.compile(parser.scope_stack).unwrap();
+            program.diagnostics.iter().map(|d| d.code).collect::<Vec<_>>()
+        };
+        assert_eq!(warnings("fn f preserves(rdi) {\n$edi = 1\n}"), [diagnostic::WRITES_PRESERVED]);
+        assert_eq!(warnings("fn f preserves(rax) {\nreturn 1\n}"), [diagnostic::WRITES_PRESERVED]);
+        // saving it with `uses` keeps the promise
+        assert!(warnings("fn f uses(rbx) preserves(rbx) {\n$rbx = 1\n}").is_empty());
+        assert!(warnings("fn f preserves(rdi) {\n$rsi = 1\n}").is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Write;
+
+use crate::span::{Span, SourceMap};
+
+// error codes, grouped by the stage that reports them
+// tokenizer
+pub const UNEXPECTED_CHARACTER: &str = "E0101";
+pub const UNKNOWN_OPERATOR: &str = "E0102";
+pub const UNTERMINATED_STRING: &str = "E0103";
+pub const INVALID_NUMBER: &str = "E0104";
+pub const MISSING_REGISTER: &str = "E0105";
+pub const UNKNOWN_REGISTER: &str = "E0106";
+pub const INVALID_ESCAPE: &str = "E0107";
+pub const INVALID_CHARACTER: &str = "E0108";
+// parser
+pub const UNEXPECTED_TOKEN: &str = "E0201";
+pub const INVALID_DESCRIPTION: &str = "E0202";
+pub const INVALID_LITERAL: &str = "E0203";
+pub const NESTED_FUNCTION: &str = "E0204";
+pub const UNBALANCED_BRACE: &str = "E0205";
+pub const OUTSIDE_OF_LOOP: &str = "E0206";
+pub const ELSE_WITHOUT_IF: &str = "E0207";
+pub const INVALID_ADDRESS: &str = "E0208";
+pub const INVALID_PARAMETER: &str = "E0209";
+pub const INVALID_ANNOTATION: &str = "E0210";
+// compiler
+pub const MISSING_TYPE: &str = "E0301";
+pub const UNSUPPORTED_OPERATION: &str = "E0302";
+pub const UNSUPPORTED_STATEMENT: &str = "E0303";
+pub const DIVISION_BY_ZERO: &str = "E0304";
+pub const IMMEDIATE_OUT_OF_RANGE: &str = "E0305";
+pub const UNKNOWN_VARIABLE: &str = "E0306";
+pub const MISMATCHED_TYPE: &str = "E0307";
+pub const READ_ONLY_STORE: &str = "E0308";
+pub const WRONG_ARGUMENT_COUNT: &str = "E0309";
+// warnings
+pub const WRITES_PRESERVED: &str = "W0301";
+pub const READ_AFTER_CLOBBER: &str = "W0302";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    // the primary label is where the error is, secondary ones give context
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, code, message.into())
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, code, message.into())
+    }
+
+    fn new(severity: Severity, code: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code,
+            message,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        // the primary label always renders first
+        self.labels.insert(0, Label { span, message: message.into(), primary: true });
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.labels.push(Label { span, message: message.into(), primary: false });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn primary_span(&self) -> Option<Span> {
+        self.labels.iter().find(|l| l.primary).map(|l| l.span)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /*
+    renders in the same shape rustc uses, e.g.
+    error[E0101]: unexpected character `@`
+     --> ops.ask:3:14
+      |
+    3 |     $rax = 2 @ 2
+      |              ^ not valid here
+     */
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut out = String::new();
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        writeln!(out, "{}[{}]: {}", severity, self.code, self.message).unwrap();
+
+        let gutter = self.labels.iter()
+            .map(|l| (sources.get(l.span.file).location(l.span.start).0 + 1).to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pad = " ".repeat(gutter);
+        for label in &self.labels {
+            let (marker, arrow) = if label.primary { ('^', "-->") } else { ('-', ":::") };
+            let file = sources.get(label.span.file);
+            let (line, col) = file.location(label.span.start);
+            let range = file.line_range(line);
+            let text = String::from_utf8_lossy(&file.text[range.clone()]);
+            // only underline up to the end of the first line
+            let width = label.span.end.min(range.end).saturating_sub(label.span.start).max(1);
+            writeln!(out, "{}{} {}:{}:{}", pad, arrow, file.name, line + 1, col + 1).unwrap();
+            writeln!(out, "{} |", pad).unwrap();
+            writeln!(out, "{:>w$} | {}", line + 1, text, w = gutter).unwrap();
+            // keep tabs so the marker lines up with the source line
+            let indent: String = text.chars().take(col).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            let underline = marker.to_string().repeat(width);
+            let marked = format!("{} | {}{} {}", pad, indent, underline, label.message);
+            writeln!(out, "{}", marked.trim_end()).unwrap();
+        }
+        for note in &self.notes {
+            writeln!(out, "{} = note: {}", pad, note).unwrap();
+        }
+        out
+    }
+}
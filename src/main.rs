@@ -1,26 +1,94 @@
+mod analysis;
 mod compiler;
 mod parser;
 mod combinator;
+mod diagnostic;
 
-use std;
 use std::str::from_utf8;
 
+mod register;
+mod span;
 mod stream;
 mod tokenizer;
 
+use diagnostic::Diagnostic;
+use span::SourceMap;
+
+fn compile(sources: &SourceMap, file: usize) -> Result<compiler::Program, Vec<Diagnostic>> {
+    let mut is = stream::IStream::new(&sources.get(file).text, file);
+    let mut tk = tokenizer::Tokenizer::new(&mut is);
+    // the tokens that could be read are parsed either way, so one bad token doesn't hide every other error
+    let mut diagnostics = tk.take_diagnostics();
+    let parsed = parser::Parser::new(tk.get_tokens());
+    let parser = match parsed {
+        Ok(parser) if diagnostics.is_empty() => parser,
+        Ok(_) => return Err(diagnostics),
+        Err(errors) => {
+            // the parser tripping over a line the tokenizer left something out of says nothing new
+            let source = sources.get(file);
+            let line = |d: &Diagnostic| d.primary_span().map(|span| source.location(span.start).0);
+            let bad_lines: Vec<_> = diagnostics.iter().map(line).collect();
+            diagnostics.extend(errors.into_iter().filter(|d| !bad_lines.contains(&line(d))));
+            return Err(diagnostics);
+        }
+    };
+    let mut program = compiler::Program::new();
+    // the analysis only warns, so its diagnostics ride along with the compiler's
+    program.diagnostics = analysis::check(&parser.scope_stack);
+    program.compile(parser.scope_stack)?;
+    Ok(program)
+}
+
 fn main() {
     let mut args = std::env::args();
     args.next();
+    let mut sources = SourceMap::new();
+    let mut failed = false;
     for argument in args {
-        let i_text = std::fs::read(&argument).unwrap();
-        let mut is = stream::IStream::new(&i_text, &argument);
-        let tk = tokenizer::Tokenizer::new(&mut is);
-        let parser = parser::Parser::new(tk.get_tokens());
-        let mut program = compiler::Program::new();
-        program.compile(parser.scope_stack);
-        println!("{}", from_utf8(&*program.header).unwrap());
-        println!("{}", from_utf8(&*program.text).unwrap());
-        println!("{}", from_utf8(&*program.ro_data).unwrap());
-        println!("{}", from_utf8(&*program.bss).unwrap());
+        let i_text = match std::fs::read(&argument) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("error: could not read {}: {}", argument, e);
+                failed = true;
+                continue;
+            }
+        };
+        let file = sources.add(argument, i_text);
+        match compile(&sources, file) {
+            Ok(program) => {
+                for d in &program.diagnostics {
+                    eprintln!("{}", d.render(&sources));
+                }
+                println!("{}", from_utf8(&program.header).unwrap());
+                println!("{}", from_utf8(&program.text).unwrap());
+                println!("{}", from_utf8(&program.data).unwrap());
+                println!("{}", from_utf8(&program.ro_data).unwrap());
+                println!("{}", from_utf8(&program.bss).unwrap());
+            }
+            Err(diagnostics) => {
+                for d in &diagnostics {
+                    eprintln!("{}", d.render(&sources));
+                }
+                failed |= diagnostics.iter().any(Diagnostic::is_error);
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_error_is_reported_in_one_run() {
+        let mut sources = SourceMap::new();
+        let text = b"fn f {\n    $foo = 1\n    $rax = (1 +\n    $rbx = 0xfg\n    if $rax = 1 {\n    }\n}\n".to_vec();
+        let file = sources.add("test.ask".to_string(), text);
+        let codes: Vec<_> = compile(&sources, file).err().unwrap().iter().map(|d| d.code).collect();
+        // the tokenizer's come first, and `$foo = 1` doesn't also get a parser error for the `=` left behind
+        assert_eq!(codes, [diagnostic::UNKNOWN_REGISTER, diagnostic::INVALID_NUMBER, diagnostic::UNEXPECTED_TOKEN, diagnostic::UNEXPECTED_TOKEN]);
     }
-}
\ No newline at end of file
+}
@@ -8,306 +8,946 @@ use crate::tokenizer::{
     Type,
     get_v_description,
     get_literal,
+    negate,
+    get_type,
+    unknown_type,
+    unknown_register,
 };
+use crate::register;
+use crate::diagnostic::{self, Diagnostic};
+use crate::span::{Span, Spanned};
 
 type Scope = Vec<Statement>;
+// (name, register it's passed in)
+type Parameters = Vec<(Vec<u8>, Vec<u8>)>;
 
 #[derive(Debug, Clone)]
 pub enum Statement {
-    NewConst { name: Identifier, literal: Literal, v_type: Option<(Type, usize)>},
-    NewLet { name: Identifier, literal: Option<Literal>, v_type: Option<(Type, usize)>},
-    RegisterAssign { register: Vec<u8>, expression: Expression },
-    RegisterDerefAssign { register: Vec<u8>, expression: Expression },
-    InlineAssembly { instructions: Vec<u8> },
-    Call { f: Vec<u8> },
+    NewConst { name: Identifier, literal: Literal, v_type: Option<(Type, usize)>, span: Span },
+    NewLet { name: Identifier, literal: Option<Literal>, v_type: Option<(Type, usize)>, span: Span },
+    RegisterAssign { register: Vec<u8>, expression: Expression, span: Span },
+    // without a width, a store is a byte, or one element for an array
+    RegisterDerefAssign { address: Address, width: Option<Type>, expression: Expression, span: Span },
+    InlineAssembly { instructions: Vec<u8>, span: Span },
+    // arguments are passed in registers like System V, the first in rdi
+    Call { f: Vec<u8>, arguments: Vec<Expression>, span: Span },
+    Return { expression: Option<Expression>, span: Span },
+    Break { span: Span },
+    Continue { span: Span },
     Scoped { scoped: ScopeImpl },
 }
 
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::NewConst { span, .. }
+            | Statement::NewLet { span, .. }
+            | Statement::RegisterAssign { span, .. }
+            | Statement::RegisterDerefAssign { span, .. }
+            | Statement::InlineAssembly { span, .. }
+            | Statement::Call { span, .. }
+            | Statement::Return { span, .. }
+            | Statement::Break { span }
+            | Statement::Continue { span } => *span,
+            Statement::Scoped { scoped } => scoped.span,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ScopeImplType {
     Global,
-    Fn { name: Vec<u8>, external: bool },
-    If { left: Expression, right: Expression, condition: Operator },
+    // params are named registers, `len: dword` as the second parameter is `esi`.
+    // scratch registers are free for expressions to overwrite, uses are callee saved registers
+    // the function saves and restores, and preserves are registers it promises not to write
+    Fn { name: Vec<u8>, external: bool, params: Parameters, scratch: Vec<Vec<u8>>, uses: Vec<Vec<u8>>, preserves: Vec<Vec<u8>> },
+    // else_scope is either an Else or, for `else if`, another If
+    If { condition: Condition, else_scope: Option<Box<ScopeImpl>> },
+    Else,
+    While { condition: Condition },
+    Loop,
+}
+
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Compare { left: Expression, right: Expression, relation: Operator, span: Span },
+    And { left: Box<Condition>, right: Box<Condition>, span: Span },
+    Or { left: Box<Condition>, right: Box<Condition>, span: Span },
+    Not { operand: Box<Condition>, span: Span },
+}
+
+impl Condition {
+    pub fn span(&self) -> Span {
+        match self {
+            Condition::Compare { span, .. }
+            | Condition::And { span, .. }
+            | Condition::Or { span, .. }
+            | Condition::Not { span, .. } => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ScopeImpl {
     pub(crate) scope_type: ScopeImplType,
     pub(crate) scope: Scope,
+    // from the opening keyword to the closing brace
+    pub(crate) span: Span,
+}
+
+impl ScopeImpl {
+    // every statement in the scope and the scopes inside of it, in the order they're compiled
+    pub fn each_statement(&self, f: &mut impl FnMut(&Statement)) {
+        for statement in &self.scope {
+            f(statement);
+            if let Statement::Scoped { scoped } = statement {
+                scoped.each_statement(f);
+            }
+        }
+        // else branches hang off of their if
+        if let ScopeImplType::If { else_scope: Some(else_scope), .. } = &self.scope_type {
+            else_scope.each_statement(f);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Expression {
-    Binary { left: Box<Expression>, right: Box<Expression>, operator: Operator },
-    Unary { operand: Box<Expression>, operator: Operator },
-    Number { value: usize },
-    Register { reg: Vec<u8> },
-    Variable { var: Vec<u8> },
-    SizeOf { var: Identifier },
+    Binary { left: Box<Expression>, right: Box<Expression>, operator: Operator, span: Span },
+    Unary { operand: Box<Expression>, operator: Operator, span: Span },
+    Number { value: usize, span: Span },
+    Register { reg: Vec<u8>, span: Span },
+    Variable { var: Vec<u8>, span: Span },
+    // without a width, a load is as wide as what it's loaded into, or one element for an array
+    Load { address: Box<Address>, width: Option<Type>, span: Span },
+}
+
+// symbol + base + index * scale + displacement, like `$*(rsi + rcx*8 + 16)` or `table[$rcx]`
+#[derive(Debug, Clone, Default)]
+pub struct Address {
+    pub symbol: Option<Vec<u8>>,
+    // when set, the index and displacement count elements of `symbol` and still have to be scaled by its type
+    pub element: bool,
+    pub base: Option<Vec<u8>>,
+    // (register, scale)
+    pub index: Option<(Vec<u8>, usize)>,
+    pub displacement: i64,
+}
+
+impl Address {
+    pub fn registers(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.base.iter().chain(self.index.iter().map(|(reg, _)| reg))
+    }
+}
+
+impl Expression {
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::Binary { span, .. }
+            | Expression::Unary { span, .. }
+            | Expression::Number { span, .. }
+            | Expression::Register { span, .. }
+            | Expression::Variable { span, .. }
+            | Expression::Load { span, .. } => *span,
+        }
+    }
 }
 
 pub struct Parser<'a> {
-    tokens: &'a Vec<Token>,
+    tokens: &'a [Spanned<Token>],
     idx: usize,
     pub scope_stack: Vec<ScopeImpl>,
+    // scope_stack depths of `{`s opened by statements that failed to parse,
+    // so their `}` doesn't close the scope around them
+    broken_scopes: Vec<usize>,
+    // scope_stack depths of open else branches
+    else_scopes: Vec<usize>,
+    diagnostics: Vec<Diagnostic>,
 }
 
-const PRECEDENCE: [Operator; 3] = [Operator::Add, Operator::Subtract, Operator::Multiply];
+
+const RELATIONS: [Operator; 10] = [
+    Operator::Equal,
+    Operator::NotEqual,
+    Operator::Less,
+    Operator::LessEqual,
+    Operator::Greater,
+    Operator::GreaterEqual,
+    Operator::SignedLess,
+    Operator::SignedLessEqual,
+    Operator::SignedGreater,
+    Operator::SignedGreaterEqual,
+];
 
 impl Parser<'_> {
-    pub fn new(tokens: &Vec<Token>) -> Parser {
+    pub fn new(tokens: &[Spanned<Token>]) -> Result<Parser<'_>, Vec<Diagnostic>> {
         let mut parser = Parser {
             tokens,
             idx: 0,
             scope_stack: vec![ScopeImpl {
                 scope_type: ScopeImplType::Global,
-                scope: vec![]
+                scope: vec![],
+                span: Span::default(),
             }],
+            broken_scopes: vec![],
+            else_scopes: vec![],
+            diagnostics: vec![],
         };
         parser.find_statements();
-        parser
+        if parser.diagnostics.is_empty() {
+            Ok(parser)
+        } else {
+            Err(parser.diagnostics)
+        }
     }
 
-    pub fn next(&mut self) -> &Token {
-        let ntoken: &Token = &self.tokens[self.idx];
-        self.idx += 1;
-        return ntoken;
+    pub fn next(&mut self) -> &Spanned<Token> {
+        let ntoken = &self.tokens[self.idx];
+        // never step past the trailing eof
+        if self.idx < self.tokens.len() - 1 {
+            self.idx += 1;
+        }
+        ntoken
     }
 
     pub fn peek(&self) -> Option<&Token> {
-        if self.idx > self.tokens.len() - 1 {
-            None
-        } else {
-            Some(&self.tokens[self.idx])
-        }
+        self.tokens.get(self.idx).map(|t| &t.node)
+    }
+
+    // span from the start of `start` to the end of the last token taken
+    fn span_from(&self, start: Span) -> Span {
+        let last = &self.tokens[self.idx.saturating_sub(1)];
+        Span::new(start.file, start.start, last.span.end.max(start.end))
     }
 
     pub fn find_statements(&mut self) {
-        while self.tokens[self.idx] != Token::Eof {
+        while self.tokens[self.idx].node != Token::Eof {
+            let start = self.idx;
             match self.get_statement() {
-                Some(s) => self.add_statement(s),
-                None => {},
+                Ok(Some(s)) => self.add_statement(s),
+                Ok(None) => {},
+                Err(d) => {
+                    self.diagnostics.push(d);
+                    self.recover(start);
+                }
             }
         }
+        for unclosed in self.scope_stack.iter().skip(1) {
+            self.diagnostics.push(Diagnostic::error(diagnostic::UNBALANCED_BRACE, "unclosed scope at end of file")
+                .with_primary(unclosed.span, "this scope is never closed")
+                .with_note("every `{` needs a matching `}`"));
+        }
     }
 
-    pub fn get_statement(&mut self) -> Option<Statement> {
-        match self.next() {
+    // skip the rest of a bad statement, stopping at the end of its line or at a `}`
+    // so the enclosing scope still gets closed
+    fn recover(&mut self, start: usize) {
+        while !matches!(self.tokens[self.idx].node, Token::Newline | Token::Separator(Separator::CloseBrace) | Token::Eof) {
+            self.idx += 1;
+        }
+        let opened = self.tokens[start..self.idx].iter()
+            .filter(|t| t.node == Token::Separator(Separator::OpenBrace))
+            .count();
+        for _ in 0..opened {
+            self.broken_scopes.push(self.scope_stack.len());
+        }
+        if self.tokens[self.idx].node == Token::Newline {
+            self.idx += 1;
+        }
+    }
+
+    pub fn get_statement(&mut self) -> Result<Option<Statement>, Diagnostic> {
+        let Spanned { node: token, span: start } = self.next().clone();
+        Ok(match token {
             Token::Keyword(kw) => match kw {
                 Keyword::Const | Keyword::Let => {
-                    let kw_copy = kw.clone();
-                    let var = match self.next() {
-                        Token::Identifier(Identifier::Variable(n)) => n.clone(),
-                        _ => panic!("const variable is invalid")
-                    };
+                    let var = self.expect_variable("a variable name")?;
                     let var_description = self.take_to_tokens(&[
                         Token::Operator(Operator::Assign),
                         Token::Newline,
                     ]);
-                    let description: Option<(Type, usize)> = if var_description.len() != 0 {
-                        Some(get_v_description(&var_description))
+                    let description: Option<(Type, usize)> = if !var_description.is_empty() {
+                        Some(get_v_description(&var_description)?)
                     } else {
                         None
                     };
-                    let mut literal_tokens = self.take_to_tokens(&[
-                        Token::Newline,
-                    ]);
-                    match kw_copy {
-                        Keyword::Const => {
-                            assert_eq!(*literal_tokens.first().unwrap(), Token::Operator(Operator::Assign));
-                            literal_tokens.remove(0);
-                            Some(Statement::NewConst {
-                                name: Identifier::Variable(var),
-                                literal: get_literal(&literal_tokens),
-                                v_type: description,
-                            })
-                        },
-                        Keyword::Let => {
-                            let literal = match &self.peek().unwrap() {
-                                Token::Operator(Operator::Assign) => {
-                                    self.next();
-                                    Some(get_literal(&self.take_to_tokens(&[Token::Newline])))
-                                },
-                                _ => None,
-                            };
-                            Some(Statement::NewLet {
-                                literal,
-                                name: Identifier::Variable(var),
-                                v_type: description,
-                            })
+                    let literal = match self.peek() {
+                        Some(Token::Operator(Operator::Assign)) => {
+                            let assign = self.next().span;
+                            let literal_tokens = self.take_to_tokens(&[Token::Newline]);
+                            Some(get_literal(&literal_tokens, assign, description)?)
                         },
-                        _ => panic!("impossible")
+                        _ => None,
+                    };
+                    let span = self.span_from(start);
+                    // parameters are resolved to their registers first, so the new variable could never be used
+                    if let Some(reg) = self.parameter(&var) {
+                        return Err(Diagnostic::error(diagnostic::INVALID_PARAMETER, format!("`{}` is already a parameter", String::from_utf8_lossy(&var)))
+                            .with_primary(span, format!("shadows the parameter in `{}`", String::from_utf8_lossy(&reg)))
+                            .with_note("give the variable a name of its own"));
+                    }
+                    if kw == Keyword::Const {
+                        Some(Statement::NewConst {
+                            name: Identifier::Variable(var),
+                            literal: literal.ok_or_else(|| {
+                                Diagnostic::error(diagnostic::INVALID_LITERAL, "constants need a value")
+                                    .with_primary(span, "constant declared without a value")
+                                    .with_note("give it one with `= <literal>`")
+                            })?,
+                            v_type: description,
+                            span,
+                        })
+                    } else {
+                        Some(Statement::NewLet {
+                            literal,
+                            name: Identifier::Variable(var),
+                            v_type: description,
+                            span,
+                        })
                     }
                 }
                 Keyword::External => {
                     // for now, only functions can be global
-                    assert_eq!(*self.next(), Token::Keyword(Keyword::Function));
-                    let name_token = self.next().clone();
-                    self.create_function(&name_token, true);
+                    self.expect(Token::Keyword(Keyword::Function), "`fn`")?;
+                    self.create_function(true, start)?;
                     None
                 },
                 Keyword::Function => {
-                    let name_token = self.next().clone();
-                    self.create_function(&name_token, false);
+                    self.create_function(false, start)?;
                     None
                 },
                 Keyword::Call => {
-                    assert_eq!(Token::Separator(Separator::OpenParentheses), *self.next());
-                    let f = self.next().clone();
-                    assert_eq!(Token::Separator(Separator::CloseParentheses), *self.next());
-                    Some(Statement::Call { f: if let Token::Identifier(Identifier::Variable(v)) = f {
-                        v.clone()
+                    self.expect(Token::Separator(Separator::OpenParentheses), "`(`")?;
+                    let f = self.expect_variable("a function name")?;
+                    let arguments = self.parse_arguments()?;
+                    Some(Statement::Call { f, arguments, span: self.span_from(start) })
+                },
+                Keyword::Return => {
+                    let expression_tokens = self.take_to_tokens(&[Token::Newline]);
+                    let expression = if expression_tokens.is_empty() {
+                        None
                     } else {
-                        panic!("bad call");
-                    }})
+                        Some(self.parse_expression(&expression_tokens, start)?)
+                    };
+                    Some(Statement::Return { expression, span: self.span_from(start) })
                 },
                 Keyword::If => {
-                    let left_tokens = self.take_to_tokens(&[
-                        Token::Operator(Operator::Equal),
-                        Token::Operator(Operator::NotEqual),
-                    ]);
-                    let left = self.parse_expression(left_tokens);
-                    let condition = self.next().clone();
-                    let right_tokens = self.take_to_tokens(&[
-                        Token::Separator(Separator::OpenBrace),
-                    ]);
-                    let right = self.parse_expression(right_tokens);
+                    let condition = self.parse_scope_condition(start)?;
+                    self.expect(Token::Separator(Separator::OpenBrace), "`{`")?;
                     let scope = ScopeImpl { scope_type: ScopeImplType::If {
-                        left,
-                        right,
-                        condition: if let Token::Operator(o) = condition {
-                            o.clone()
-                        } else {
-                            panic!("bad if statement");
-                        }
-                    }, scope: vec![] };
+                        condition,
+                        else_scope: None,
+                    }, scope: vec![], span: self.span_from(start) };
                     self.scope_stack.push(scope);
                     None
                 },
-                _ => None
-            }
-            Token::Separator(sep) => match sep {
-                Separator::CloseBrace => {
-                    let done_scope = self.scope_stack.pop().unwrap();
-                    self.add_statement(Statement::Scoped { scoped: done_scope });
+                Keyword::Else => {
+                    let has_if = match self.scope_stack.last_mut().unwrap().scope.last_mut() {
+                        Some(Statement::Scoped { scoped }) => open_else(scoped).is_some(),
+                        _ => false,
+                    };
+                    if !has_if {
+                        return Err(Diagnostic::error(diagnostic::ELSE_WITHOUT_IF, "`else` without a matching `if`")
+                            .with_primary(start, "must come right after an `if` scope"));
+                    }
+                    let scope_type = if self.peek() == Some(&Token::Keyword(Keyword::If)) {
+                        self.next();
+                        let condition = self.parse_scope_condition(start)?;
+                        ScopeImplType::If { condition, else_scope: None }
+                    } else {
+                        ScopeImplType::Else
+                    };
+                    self.expect(Token::Separator(Separator::OpenBrace), "`{`")?;
+                    self.else_scopes.push(self.scope_stack.len());
+                    self.scope_stack.push(ScopeImpl { scope_type, scope: vec![], span: self.span_from(start) });
                     None
                 },
-                _ => None,
-            }
-            Token::Identifier(id) => match id {
-                Identifier::Register(r) => {
-                    let r_copy = r.to_vec();
-                    assert_eq!(Token::Operator(Operator::Assign), *self.next());
-                    let expression_tokens = self.take_to_tokens(&[Token::Newline]);
-                    let expression = self.parse_expression(expression_tokens);
-                    Some(Statement::RegisterAssign {
-                        register: r_copy,
-                        expression
-                    })
+                Keyword::While => {
+                    let condition = self.parse_scope_condition(start)?;
+                    self.expect(Token::Separator(Separator::OpenBrace), "`{`")?;
+                    let scope = ScopeImpl { scope_type: ScopeImplType::While {
+                        condition,
+                    }, scope: vec![], span: self.span_from(start) };
+                    self.scope_stack.push(scope);
+                    None
                 },
-                Identifier::DerefRegister(r) => {
-                    let r_copy = r.to_vec();
-                    assert_eq!(Token::Operator(Operator::Assign), *self.next());
-                    let expression_tokens = self.take_to_tokens(&[Token::Newline]);
-                    let expression = self.parse_expression(expression_tokens);
-                    Some(Statement::RegisterDerefAssign {
-                        register: r_copy,
-                        expression
-                    })
+                Keyword::Loop => {
+                    self.expect(Token::Separator(Separator::OpenBrace), "`{`")?;
+                    self.scope_stack.push(ScopeImpl {
+                        scope_type: ScopeImplType::Loop,
+                        scope: vec![],
+                        span: self.span_from(start),
+                    });
+                    None
+                },
+                Keyword::Break | Keyword::Continue => {
+                    let in_loop = self.scope_stack.iter().rev()
+                        .take_while(|scope| !matches!(scope.scope_type, ScopeImplType::Fn { .. }))
+                        .any(|scope| matches!(scope.scope_type, ScopeImplType::While { .. } | ScopeImplType::Loop));
+                    if !in_loop {
+                        return Err(Diagnostic::error(diagnostic::OUTSIDE_OF_LOOP, format!("`{}` outside of a loop", kw))
+                            .with_primary(start, "can only be used inside `while` or `loop`"));
+                    }
+                    if kw == Keyword::Break {
+                        Some(Statement::Break { span: start })
+                    } else {
+                        Some(Statement::Continue { span: start })
+                    }
+                },
+                Keyword::SizeOf => return Err(unexpected(&Spanned { node: Token::Keyword(kw), span: start }, "a statement")),
+            }
+            Token::Separator(Separator::CloseBrace) => {
+                if self.broken_scopes.last() == Some(&self.scope_stack.len()) {
+                    self.broken_scopes.pop();
+                    return Ok(None);
+                }
+                if self.scope_stack.len() == 1 {
+                    return Err(Diagnostic::error(diagnostic::UNBALANCED_BRACE, "unexpected `}`")
+                        .with_primary(start, "there is no open scope to close"));
+                }
+                let mut done_scope = self.scope_stack.pop().unwrap();
+                done_scope.span = self.span_from(done_scope.span);
+                if self.else_scopes.last() == Some(&self.scope_stack.len()) {
+                    // else branches hang off of the if they follow instead of being statements
+                    self.else_scopes.pop();
+                    if let Some(Statement::Scoped { scoped }) = self.scope_stack.last_mut().unwrap().scope.last_mut() {
+                        scoped.span.end = done_scope.span.end;
+                        if let Some(else_scope) = open_else(scoped) {
+                            *else_scope = Some(Box::new(done_scope));
+                        }
+                    }
+                    return Ok(None);
                 }
-                _ => None,
+                Some(Statement::Scoped { scoped: done_scope })
+            }
+            Token::Identifier(Identifier::Register(r)) => {
+                let assign = self.expect(Token::Operator(Operator::Assign), "`=`")?;
+                let expression_tokens = self.take_to_tokens(&[Token::Newline]);
+                let expression = self.parse_expression(&expression_tokens, assign)?;
+                Some(Statement::RegisterAssign {
+                    register: r,
+                    expression,
+                    span: self.span_from(start),
+                })
             },
+            Token::Identifier(Identifier::DerefRegister(_)) | Token::Operator(Operator::Deref) => self.parse_store(start)?,
+            Token::Identifier(Identifier::Variable(_)) if self.peek() == Some(&Token::Separator(Separator::OpenBracket)) => self.parse_store(start)?,
             Token::Inline(inline) => Some(Statement::InlineAssembly {
-                instructions: inline.to_vec(),
+                instructions: inline,
+                span: start,
             }),
-            _ => None
+            Token::Newline | Token::Eof => None,
+            token => return Err(unexpected(&Spanned { node: token, span: start }, "a statement")),
+        })
+    }
+
+    // `$*rdi = ...` or `table[$rcx] = ...`, the address is parsed again from its first token
+    fn parse_store(&mut self, start: Span) -> Result<Option<Statement>, Diagnostic> {
+        self.idx -= 1;
+        let address_tokens = self.take_to_tokens(&[Token::Operator(Operator::Assign), Token::Newline]);
+        let mut pos = 0;
+        let (address, _) = self.parse_address(&address_tokens, &mut pos)?;
+        let width = self.parse_width(&address_tokens, &mut pos)?;
+        if let Some(t) = address_tokens.get(pos) {
+            return Err(unexpected(t, "`=`"));
         }
+        let assign = self.expect(Token::Operator(Operator::Assign), "`=`")?;
+        let expression_tokens = self.take_to_tokens(&[Token::Newline]);
+        let expression = self.parse_expression(&expression_tokens, assign)?;
+        Ok(Some(Statement::RegisterDerefAssign {
+            address,
+            width,
+            expression,
+            span: self.span_from(start),
+        }))
     }
 
-    pub fn parse_expression(&self, tks: Vec<Token>) -> Expression {
-        let op_pos = {
-            let mut ret: Option<usize> = None;
-            for check_op in PRECEDENCE.iter() {
-                 if let Some(i) = tks.iter().position(|token| {
-                    let op = if let Token::Operator(op) = token {
-                        op
-                    } else {
-                        return false;
-                    };
-                    check_op == op
-                 }) {
-                     ret = Some(i);
-                     break;
-                 }
+    // the condition of an if or while, up to the opening brace
+    fn parse_scope_condition(&mut self, start: Span) -> Result<Condition, Diagnostic> {
+        let tokens = self.take_to_tokens(&[
+            Token::Separator(Separator::OpenBrace),
+            Token::Newline,
+        ]);
+        let mut pos = 0;
+        let condition = self.parse_condition(&tokens, &mut pos, start)?;
+        match tokens.get(pos) {
+            Some(t) => Err(unexpected(t, "`&&`, `||` or `{`")),
+            None => Ok(condition),
+        }
+    }
+
+    // conditions are `||`s of `&&`s of comparisons, which can be negated with `!` and grouped with parentheses
+    fn parse_condition(&self, tks: &[Spanned<Token>], pos: &mut usize, after: Span) -> Result<Condition, Diagnostic> {
+        let mut left = self.parse_and_condition(tks, pos, after)?;
+        while tks.get(*pos).map(|t| &t.node) == Some(&Token::Operator(Operator::LogicalOr)) {
+            let or = tks[*pos].span;
+            *pos += 1;
+            let right = self.parse_and_condition(tks, pos, or)?;
+            let span = left.span().to(right.span());
+            left = Condition::Or { left: Box::new(left), right: Box::new(right), span };
+        }
+        Ok(left)
+    }
+
+    fn parse_and_condition(&self, tks: &[Spanned<Token>], pos: &mut usize, after: Span) -> Result<Condition, Diagnostic> {
+        let mut left = self.parse_not_condition(tks, pos, after)?;
+        while tks.get(*pos).map(|t| &t.node) == Some(&Token::Operator(Operator::LogicalAnd)) {
+            let and = tks[*pos].span;
+            *pos += 1;
+            let right = self.parse_not_condition(tks, pos, and)?;
+            let span = left.span().to(right.span());
+            left = Condition::And { left: Box::new(left), right: Box::new(right), span };
+        }
+        Ok(left)
+    }
+
+    fn parse_not_condition(&self, tks: &[Spanned<Token>], pos: &mut usize, after: Span) -> Result<Condition, Diagnostic> {
+        match tks.get(*pos).map(|t| &t.node) {
+            Some(Token::Operator(Operator::Not)) => {
+                let not = tks[*pos].span;
+                *pos += 1;
+                let operand = self.parse_not_condition(tks, pos, not)?;
+                let span = not.to(operand.span());
+                Ok(Condition::Not { operand: Box::new(operand), span })
+            }
+            Some(Token::Separator(Separator::OpenParentheses)) if is_condition_group(&tks[*pos..]) => {
+                let open = tks[*pos].span;
+                *pos += 1;
+                let inner = self.parse_condition(tks, pos, open)?;
+                match tks.get(*pos) {
+                    Some(Spanned { node: Token::Separator(Separator::CloseParentheses), .. }) => {
+                        *pos += 1;
+                        Ok(inner)
+                    }
+                    found => Err(unexpected(found.unwrap_or(&self.tokens[self.idx]), "`)`")),
+                }
             }
-            ret
+            _ => self.parse_compare(tks, pos, after),
+        }
+    }
+
+    // `left <relation> right`
+    fn parse_compare(&self, tks: &[Spanned<Token>], pos: &mut usize, after: Span) -> Result<Condition, Diagnostic> {
+        let left_tokens = take_operand(tks, pos, true);
+        let left = self.parse_expression(left_tokens, after)?;
+        let relation = match tks.get(*pos) {
+            Some(Spanned { node: Token::Operator(o), .. }) if RELATIONS.contains(o) => o.clone(),
+            found => return Err(unexpected(found.unwrap_or(&self.tokens[self.idx]), "a comparison like `==` or `<`")),
         };
-        match op_pos {
-            Some(op) => {
-                let left = &tks[..op];
-                let right = &tks[op + 1..];
-                Expression::Binary {
-                    left: Box::new(self.parse_expression(left.to_vec())),
-                    right: Box::new(self.parse_expression(right.to_vec())),
-                    operator: if let Token::Operator(operator) = tks[op].clone() {
-                        operator
-                    } else {
-                        panic!("impossible");
+        let relation_span = tks[*pos].span;
+        *pos += 1;
+        let right_tokens = take_operand(tks, pos, false);
+        let right = self.parse_expression(right_tokens, relation_span)?;
+        let span = left.span().to(right.span());
+        Ok(Condition::Compare { left, right, relation, span })
+    }
+
+    // `after` is the token right before the expression, used to point at missing expressions
+    pub fn parse_expression(&self, tks: &[Spanned<Token>], after: Span) -> Result<Expression, Diagnostic> {
+        let mut pos = 0;
+        let expression = self.parse_binary(tks, &mut pos, 0, after)?;
+        match tks.get(pos) {
+            Some(t) => Err(unexpected(t, "an operator or end of expression")),
+            None => Ok(expression),
+        }
+    }
+
+    /*
+    precedence climbing, for `a - b * c - d` we take `a`, then every operator binding at least as
+    tight as `min_precedence` along with everything to its right that binds tighter
+            -
+          /   \
+         -     d
+       /   \
+      a     *
+          /   \
+         b     c
+     */
+    fn parse_binary(&self, tks: &[Spanned<Token>], pos: &mut usize, min_precedence: u8, after: Span) -> Result<Expression, Diagnostic> {
+        let mut left = self.parse_atom(tks, pos, after)?;
+        while let Some(Spanned { node: Token::Operator(operator), span: op_span }) = tks.get(*pos) {
+            let op_precedence = match precedence(operator) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
+            };
+            *pos += 1;
+            // only tighter operators go on the right, which keeps equal ones left associative
+            let right = self.parse_binary(tks, pos, op_precedence + 1, *op_span)?;
+            let span = left.span().to(right.span());
+            left = Expression::Binary {
+                left: Box::new(left),
+                right: Box::new(right),
+                operator: operator.clone(),
+                span,
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_atom(&self, tks: &[Spanned<Token>], pos: &mut usize, after: Span) -> Result<Expression, Diagnostic> {
+        let first = match tks.get(*pos) {
+            Some(first) => first,
+            None => return Err(Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, "expected an expression")
+                .with_primary(Span::new(after.file, after.end, after.end), "expected an expression after this")),
+        };
+        let span = first.span;
+        *pos += 1;
+        Ok(match &first.node {
+            Token::Literal(Literal::Int(i)) => Expression::Number { value: *i, span },
+            Token::Identifier(Identifier::Register(r)) => Expression::Register {
+                reg: r.to_vec(),
+                span,
+            },
+            Token::Identifier(Identifier::Variable(_)) if tks.get(*pos).map(|t| &t.node) == Some(&Token::Separator(Separator::OpenBracket)) => {
+                *pos -= 1;
+                let (address, span) = self.parse_address(tks, pos)?;
+                let width = self.parse_width(tks, pos)?;
+                Expression::Load { address: Box::new(address), width, span: span.to(tks[*pos - 1].span) }
+            }
+            Token::Identifier(Identifier::Variable(v)) => match self.parameter(v) {
+                Some(reg) => Expression::Register { reg, span },
+                None => Expression::Variable { var: v.to_vec(), span },
+            },
+            Token::Keyword(Keyword::SizeOf) => {
+                let inner: Vec<&Token> = tks[*pos..].iter().take(3).map(|t| &t.node).collect();
+                match &inner[..] {
+                    [Token::Separator(Separator::OpenParentheses), Token::Identifier(Identifier::Variable(v)), Token::Separator(Separator::CloseParentheses)] => {
+                        let mut vc = v.to_vec().to_ascii_uppercase();
+                        vc.extend_from_slice(b"_LEN");
+                        *pos += 3;
+                        Expression::Variable { var: vc, span: span.to(tks[*pos - 1].span) }
                     }
+                    _ => return Err(Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, "invalid `sizeof`")
+                        .with_primary(span, "expected a variable in parentheses after this")
+                        .with_note("`sizeof` takes a single variable, like `sizeof(buffer)`")),
                 }
+            },
+            Token::Identifier(Identifier::DerefRegister(_)) | Token::Operator(Operator::Deref) => {
+                *pos -= 1;
+                let (address, span) = self.parse_address(tks, pos)?;
+                let width = self.parse_width(tks, pos)?;
+                Expression::Load { address: Box::new(address), width, span: span.to(tks[*pos - 1].span) }
             }
-            None => {
-                // binary expression not applicable
-                // for now just check if it's a number
-                match &tks[0] {
-                    Token::Literal(Literal::Int(i)) => Expression::Number { value: *i },
-                    Token::Identifier(Identifier::Register(r)) => Expression::Register {
-                        reg: r.to_vec()
-                    },
-                    Token::Keyword(Keyword::SizeOf) => {
-                        assert_eq!(Token::Separator(Separator::OpenParentheses), tks[1]);
-                        assert_eq!(Token::Separator(Separator::CloseParentheses), tks[3]);
-                        Expression::Variable {
-                            var: if let Token::Identifier(Identifier::Variable(v)) = tks[2].clone() {
-                                let mut vc = v.to_vec().to_ascii_uppercase();
-                                vc.extend_from_slice(b"_LEN");
-                                vc
-                            } else {
-                                panic!("bad token for sizeof")
-                            },
+            // unary operators bind tighter than any binary one
+            Token::Operator(operator @ (Operator::BitNot | Operator::Subtract)) => {
+                let literal = matches!(tks.get(*pos).map(|t| &t.node), Some(Token::Literal(Literal::Int(_))));
+                let operand = self.parse_atom(tks, pos, span)?;
+                // a negative literal has to fit in 64 bits, the same as in a `const`
+                if let (Operator::Subtract, true, Expression::Number { value, span: number }) = (operator, literal, &operand) {
+                    negate(*value, span.to(*number))?;
+                }
+                Expression::Unary {
+                    span: span.to(operand.span()),
+                    operand: Box::new(operand),
+                    operator: operator.clone(),
+                }
+            }
+            Token::Separator(Separator::OpenParentheses) => {
+                let inner = self.parse_binary(tks, pos, 0, span)?;
+                match tks.get(*pos) {
+                    Some(Spanned { node: Token::Separator(Separator::CloseParentheses), .. }) => {
+                        *pos += 1;
+                        inner
+                    }
+                    found => return Err(unexpected(found.unwrap_or(&self.tokens[self.idx]), "`)`")
+                        .with_secondary(span, "to close this")),
+                }
+            }
+            _ => return Err(unexpected(first, "an expression")),
+        })
+    }
+
+    /*
+    `$*rsi`, or `$*(...)` with a sum of registers, scaled registers and numbers like
+    `$*(rsi + rcx*8 - 16)`. registers inside the parentheses don't need a `$`.
+    `table[$rcx + 1]` is also an address, counted in elements of table
+     */
+    fn parse_address(&self, tks: &[Spanned<Token>], pos: &mut usize) -> Result<(Address, Span), Diagnostic> {
+        let start = tks[*pos].span;
+        *pos += 1;
+        let invalid = |message: &str, span: Span, label: &str| Diagnostic::error(diagnostic::INVALID_ADDRESS, message)
+            .with_primary(span, label)
+            .with_note("addresses look like `$*(base + index*scale + displacement)`, with a scale of 1, 2, 4 or 8");
+        let check_register = |r: &[u8], span: Span| {
+            if !register::is_register(r) {
+                Err(unknown_register(r, span))
+            } else if register::width(r) != Type::Uint64 {
+                Err(invalid("addresses are made of qword registers", span, "not a qword register"))
+            } else {
+                Ok(())
+            }
+        };
+        if let Token::Identifier(Identifier::DerefRegister(r)) = &tks[*pos - 1].node {
+            check_register(r, start)?;
+            return Ok((Address { base: Some(r.clone()), ..Address::default() }, start));
+        }
+        let found = |pos: usize| tks.get(pos).unwrap_or(&self.tokens[self.idx]);
+        if let Token::Identifier(Identifier::Variable(array)) = &tks[*pos - 1].node {
+            let mut address = Address { symbol: Some(array.clone()), element: true, ..Address::default() };
+            *pos += 1;
+            let mut subtract = false;
+            loop {
+                let term = found(*pos);
+                match &term.node {
+                    Token::Identifier(Identifier::Register(_)) if subtract => {
+                        return Err(invalid("registers can't be subtracted in an index", term.span, "can only be added"));
+                    }
+                    Token::Identifier(Identifier::Register(r)) => {
+                        check_register(r, term.span)?;
+                        if address.index.is_some() {
+                            return Err(invalid("an index can only have one register", term.span, "second register"));
                         }
-                    },
-                    Token::Identifier(Identifier::Variable(v)) => Expression::Variable { var: v.to_vec() },
-                    _ => panic!("bad token")
+                        address.index = Some((r.clone(), 1));
+                    }
+                    Token::Literal(Literal::Int(i)) => {
+                        let value = if subtract { (*i as i64).wrapping_neg() } else { *i as i64 };
+                        address.displacement = address.displacement.wrapping_add(value);
+                    }
+                    _ => return Err(unexpected(term, "a register or number")),
+                }
+                *pos += 1;
+                match &found(*pos).node {
+                    Token::Operator(Operator::Add) => subtract = false,
+                    Token::Operator(Operator::Subtract) => subtract = true,
+                    Token::Separator(Separator::CloseBracket) => break,
+                    _ => return Err(unexpected(found(*pos), "`+`, `-` or `]`")),
+                }
+                *pos += 1;
+            }
+            *pos += 1;
+            return Ok((address, start.to(tks[*pos - 1].span)));
+        }
+        if tks.get(*pos).map(|t| &t.node) != Some(&Token::Separator(Separator::OpenParentheses)) {
+            return Err(unexpected(found(*pos), "`(`"));
+        }
+        *pos += 1;
+        let mut address = Address::default();
+        let mut subtract = false;
+        loop {
+            let term_start = found(*pos).span;
+            let (register, value) = match &found(*pos).node {
+                Token::Identifier(Identifier::Register(r)) => (Some(r.clone()), None),
+                Token::Identifier(Identifier::Variable(r)) => (Some(self.parameter(r).unwrap_or_else(|| r.clone())), None),
+                Token::Literal(Literal::Int(i)) => (None, Some(*i)),
+                _ => return Err(unexpected(found(*pos), "a register or number")),
+            };
+            *pos += 1;
+            // `rcx*8` or `8*rcx`
+            let (register, value) = if tks.get(*pos).map(|t| &t.node) == Some(&Token::Operator(Operator::Multiply)) {
+                *pos += 1;
+                let scaled = match (register, value, &found(*pos).node) {
+                    (Some(r), None, Token::Literal(Literal::Int(i))) => (Some(r), Some(*i)),
+                    (None, Some(i), Token::Identifier(Identifier::Register(r))) => (Some(r.clone()), Some(i)),
+                    (None, Some(i), Token::Identifier(Identifier::Variable(r))) => (Some(self.parameter(r).unwrap_or_else(|| r.clone())), Some(i)),
+                    _ => return Err(invalid("only a register can be scaled by a number", term_start.to(found(*pos).span), "can't be used in an address")),
+                };
+                *pos += 1;
+                scaled
+            } else {
+                (register, value)
+            };
+            let span = term_start.to(tks[*pos - 1].span);
+            if let Some(r) = &register {
+                check_register(r, span)?;
+            }
+            match (register, value) {
+                (Some(_), _) if subtract => return Err(invalid("registers can't be subtracted in an address", span, "can only be added")),
+                (Some(reg), Some(scale)) => {
+                    if !matches!(scale, 1 | 2 | 4 | 8) {
+                        return Err(invalid("invalid scale in address", span, "scale has to be 1, 2, 4 or 8"));
+                    }
+                    if address.index.is_some() {
+                        return Err(invalid("an address can only have one scaled register", span, "second scaled register"));
+                    }
+                    address.index = Some((reg, scale));
+                }
+                (Some(reg), None) => {
+                    if address.base.is_none() {
+                        address.base = Some(reg);
+                    } else if address.index.is_none() {
+                        address.index = Some((reg, 1));
+                    } else {
+                        return Err(invalid("an address can only add up to two registers", span, "one register too many"));
+                    }
                 }
+                (None, Some(value)) => {
+                    let value = if subtract { (value as i64).wrapping_neg() } else { value as i64 };
+                    address.displacement = address.displacement.wrapping_add(value);
+                }
+                (None, None) => unreachable!("every term is a register or a number"),
+            }
+            match &found(*pos).node {
+                Token::Operator(Operator::Add) => subtract = false,
+                Token::Operator(Operator::Subtract) => subtract = true,
+                Token::Separator(Separator::CloseParentheses) => break,
+                _ => return Err(unexpected(found(*pos), "`+`, `-` or `)`")),
             }
+            *pos += 1;
         }
+        *pos += 1;
+        let span = start.to(tks[*pos - 1].span);
+        if address.displacement < i32::MIN as i64 || address.displacement > i32::MAX as i64 {
+            return Err(invalid("address displacement is too large", span, "displacement doesn't fit in 32 bits"));
+        }
+        Ok((address, span))
     }
 
-    // pub fn r_parse_expression(tree: Expression) -> Expression {
-    //     tree
-    // }
+    // the optional `:dword` after an address
+    fn parse_width(&self, tks: &[Spanned<Token>], pos: &mut usize) -> Result<Option<Type>, Diagnostic> {
+        if tks.get(*pos).map(|t| &t.node) != Some(&Token::Separator(Separator::Colon)) {
+            return Ok(None);
+        }
+        *pos += 1;
+        match tks.get(*pos) {
+            Some(Spanned { node: Token::Identifier(Identifier::Variable(t)), span }) => {
+                *pos += 1;
+                get_type(t).map(Some).ok_or_else(|| unknown_type(t, *span))
+            }
+            found => Err(unexpected(found.unwrap_or(&self.tokens[self.idx]), "a type")),
+        }
+    }
 
-    pub fn create_function(&mut self, name: &Token, external: bool) {
-        let name = match name {
-            Token::Identifier(Identifier::Variable(n)) => n.clone(),
-            _ => panic!("token is bad"),
+    pub fn create_function(&mut self, external: bool, start: Span) -> Result<(), Diagnostic> {
+        let name = self.expect_variable("a function name")?;
+        // `fn _start()` is allowed as well as `fn _start`
+        let params = if self.peek() == Some(&Token::Separator(Separator::OpenParentheses)) {
+            self.parse_parameters()?
+        } else {
+            vec![]
         };
+        let mut scratch = vec![];
+        let mut uses = vec![];
+        let mut preserves = vec![];
+        // annotations can come in any order, like `fn foo scratch(r10) uses(rbx) {`
+        while let Some(Token::Identifier(Identifier::Variable(v))) = self.peek() {
+            let list = match v.as_slice() {
+                b"scratch" => &mut scratch,
+                b"uses" => &mut uses,
+                b"preserves" => &mut preserves,
+                _ => break,
+            };
+            let annotation = self.next().span;
+            *list = self.parse_register_list()?;
+            if let Some(r) = uses.iter().find(|r| !register::CALLEE_SAVED.contains(&register::family(r))) {
+                return Err(Diagnostic::error(diagnostic::INVALID_ANNOTATION, format!("`{}` isn't callee saved", String::from_utf8_lossy(r)))
+                    .with_primary(self.span_from(annotation), "only callee saved registers can be used")
+                    .with_note("callee saved registers are `rbx`, `rbp` and `r12` to `r15`, declare others with `scratch`"));
+            }
+        }
+        self.expect(Token::Separator(Separator::OpenBrace), "`{`")?;
+        let span = self.span_from(start);
         // check for nested functions
-        if self.scope_stack.iter().any(|scope| match &scope.scope_type {
-            ScopeImplType::Fn { name, external } => true,
-            _ => false,
-        }) {
-            panic!("no nested functions");
+        if let Some(outer) = self.scope_stack.iter().find(|scope| matches!(scope.scope_type, ScopeImplType::Fn { .. })) {
+            return Err(Diagnostic::error(diagnostic::NESTED_FUNCTION, "functions cannot be nested")
+                .with_primary(span, "function declared here")
+                .with_secondary(outer.span, "inside of this function"));
         }
         self.scope_stack.push(ScopeImpl {
             scope: vec![],
             scope_type: ScopeImplType::Fn {
                 name,
                 external,
+                params,
+                scratch,
+                uses,
+                preserves,
+            },
+            span,
+        });
+        Ok(())
+    }
+
+    // `(buf: reg, len: dword)`, each parameter takes the next argument register at its type
+    fn parse_parameters(&mut self) -> Result<Parameters, Diagnostic> {
+        let open = self.expect(Token::Separator(Separator::OpenParentheses), "`(`")?;
+        let mut params: Parameters = vec![];
+        if self.peek() == Some(&Token::Separator(Separator::CloseParentheses)) {
+            self.next();
+            return Ok(params);
+        }
+        loop {
+            let name = self.expect_variable("a parameter name")?;
+            let name_span = self.tokens[self.idx - 1].span;
+            if params.iter().any(|(n, _)| *n == name) {
+                return Err(Diagnostic::error(diagnostic::INVALID_PARAMETER, format!("`{}` is already a parameter", String::from_utf8_lossy(&name)))
+                    .with_primary(name_span, "used twice"));
+            }
+            if params.len() == register::ARGUMENTS.len() {
+                return Err(Diagnostic::error(diagnostic::INVALID_PARAMETER, "too many parameters")
+                    .with_primary(name_span, "no register left to pass this in")
+                    .with_secondary(open, "parameters start here")
+                    .with_note("only the first six arguments are passed in registers, in `rdi`, `rsi`, `rdx`, `rcx`, `r8` and `r9`"));
+            }
+            self.expect(Token::Separator(Separator::Colon), "`:`")?;
+            let t = self.expect_variable("a type")?;
+            // a `reg` is the whole register
+            let width = match get_type(&t) {
+                Some(width) => width,
+                None if t == b"reg" => Type::Uint64,
+                None => return Err(unknown_type(&t, self.tokens[self.idx - 1].span).with_note("a parameter can also be a `reg`")),
+            };
+            let register = register::sized(register::ARGUMENTS[params.len()].as_bytes(), width);
+            params.push((name, register.as_bytes().to_vec()));
+            match self.next() {
+                Spanned { node: Token::Separator(Separator::Comma), .. } => {}
+                Spanned { node: Token::Separator(Separator::CloseParentheses), .. } => return Ok(params),
+                t => return Err(unexpected(t, "`,` or `)`")),
             }
+        }
+    }
+
+    // `, hello, sizeof(hello))` after the function being called
+    fn parse_arguments(&mut self) -> Result<Vec<Expression>, Diagnostic> {
+        let tokens = self.take_to_tokens(&[Token::Newline]);
+        let mut arguments = vec![];
+        let mut pos = 0;
+        loop {
+            let found = tokens.get(pos).unwrap_or(&self.tokens[self.idx]);
+            match &found.node {
+                Token::Separator(Separator::CloseParentheses) if pos + 1 == tokens.len() => break,
+                Token::Separator(Separator::Comma) => {
+                    pos += 1;
+                    arguments.push(self.parse_binary(&tokens, &mut pos, 0, found.span)?);
+                }
+                _ => return Err(unexpected(found, "`,` or `)`")),
+            }
+        }
+        if arguments.len() > register::ARGUMENTS.len() {
+            return Err(Diagnostic::error(diagnostic::INVALID_PARAMETER, "too many arguments")
+                .with_primary(arguments[register::ARGUMENTS.len()].span(), "no register left to pass this in")
+                .with_note("only the first six arguments are passed in registers, in `rdi`, `rsi`, `rdx`, `rcx`, `r8` and `r9`"));
+        }
+        Ok(arguments)
+    }
+
+    // the register a parameter of the function being parsed was passed in
+    fn parameter(&self, name: &[u8]) -> Option<Vec<u8>> {
+        self.scope_stack.iter().find_map(|scope| match &scope.scope_type {
+            ScopeImplType::Fn { params, .. } => params.iter().find(|(n, _)| n == name).map(|(_, reg)| reg.clone()),
+            _ => None,
         })
+    }
 
+    // `(rbx, r12)`, registers are named without a `$`
+    fn parse_register_list(&mut self) -> Result<Vec<Vec<u8>>, Diagnostic> {
+        self.expect(Token::Separator(Separator::OpenParentheses), "`(`")?;
+        let mut registers = vec![];
+        if self.peek() == Some(&Token::Separator(Separator::CloseParentheses)) {
+            self.next();
+            return Ok(registers);
+        }
+        loop {
+            let register = self.expect_variable("a register name")?;
+            if !register::is_register(&register) {
+                return Err(unknown_register(&register, self.tokens[self.idx - 1].span));
+            }
+            registers.push(register);
+            match self.next() {
+                Spanned { node: Token::Separator(Separator::Comma), .. } => {}
+                Spanned { node: Token::Separator(Separator::CloseParentheses), .. } => return Ok(registers),
+                t => return Err(unexpected(t, "`,` or `)`")),
+            }
+        }
     }
 
     pub fn add_statement(&mut self, s: Statement) {
@@ -315,33 +955,264 @@ impl Parser<'_> {
         self.scope_stack[len - 1].scope.push(s);
     }
 
-    pub fn get_statements(&self) -> &ScopeImpl {
-        self.scope_stack.first().unwrap()
-    }
-
-    pub fn take_to_tokens(&mut self, tks: &[Token]) -> Vec<Token> {
+    pub fn take_to_tokens(&mut self, tks: &[Token]) -> Vec<Spanned<Token>> {
+        // eof always ends the run so a missing delimiter can't read past the end
         let taken = self.tokens[self.idx..].iter().take_while(|curr_token| {
-            for tk in tks {
-                if tk == *curr_token {
-                    return false;
-                }
-            }
-            return true;
+            curr_token.node != Token::Eof && !tks.contains(&curr_token.node)
         });
-        let res: Vec<Token> = taken.cloned().collect();
+        let res: Vec<Spanned<Token>> = taken.cloned().collect();
         self.idx += res.len();
         res
     }
 
-    pub fn take_given_to_tokens(given_tks: &Vec<Token>, tks: &[Token]) -> Vec<Token> {
-        let taken = given_tks.iter().take_while(|curr_token| {
-            for tk in tks {
-                if tk == *curr_token {
+    // returns the span of the expected token
+    fn expect(&mut self, token: Token, what: &str) -> Result<Span, Diagnostic> {
+        let found = self.next();
+        if found.node == token {
+            Ok(found.span)
+        } else {
+            Err(unexpected(found, what))
+        }
+    }
+
+    fn expect_variable(&mut self, what: &str) -> Result<Vec<u8>, Diagnostic> {
+        match self.next() {
+            Spanned { node: Token::Identifier(Identifier::Variable(v)), .. } => Ok(v.clone()),
+            t => Err(unexpected(t, what)),
+        }
+    }
+}
+
+// how tightly a binary operator binds, higher goes first
+fn precedence(operator: &Operator) -> Option<u8> {
+    match operator {
+        // like C, except comparisons aren't part of expressions
+        Operator::BitOr => Some(1),
+        Operator::BitXor => Some(2),
+        Operator::BitAnd => Some(3),
+        Operator::ShiftLeft | Operator::ShiftRight | Operator::SignedShiftRight => Some(4),
+        Operator::Add | Operator::Subtract => Some(5),
+        Operator::Multiply | Operator::Divide | Operator::Modulus | Operator::SignedDivide | Operator::SignedModulus => Some(6),
+        _ => None,
+    }
+}
+
+// the tokens of one side of a comparison, which ends at a `&&`, `||`, unopened `)`,
+// or if it's the left side, a relation
+fn take_operand<'t>(tks: &'t [Spanned<Token>], pos: &mut usize, left: bool) -> &'t [Spanned<Token>] {
+    let start = *pos;
+    let mut depth = 0;
+    while let Some(token) = tks.get(*pos) {
+        match &token.node {
+            Token::Separator(Separator::OpenParentheses) => depth += 1,
+            Token::Separator(Separator::CloseParentheses) if depth == 0 => break,
+            Token::Separator(Separator::CloseParentheses) => depth -= 1,
+            Token::Operator(Operator::LogicalAnd) | Token::Operator(Operator::LogicalOr) if depth == 0 => break,
+            Token::Operator(o) if left && depth == 0 && RELATIONS.contains(o) => break,
+            _ => {}
+        }
+        *pos += 1;
+    }
+    &tks[start..*pos]
+}
+
+// whether the parentheses starting `tks` hold a condition rather than part of an expression,
+// which is the case when they contain something only conditions can
+fn is_condition_group(tks: &[Spanned<Token>]) -> bool {
+    if tks.get(1).map(|t| &t.node) == Some(&Token::Operator(Operator::Not)) {
+        return true;
+    }
+    // a group holding nothing but another group, `((a == b))`, is whatever the inner one is
+    if let Some(end) = tks.get(1..).and_then(group_end) {
+        if tks.get(end + 2).map(|t| &t.node) == Some(&Token::Separator(Separator::CloseParentheses)) {
+            return is_condition_group(&tks[1..]);
+        }
+    }
+    let mut depth = 0;
+    for token in tks {
+        match &token.node {
+            Token::Separator(Separator::OpenParentheses) => depth += 1,
+            Token::Separator(Separator::CloseParentheses) => {
+                depth -= 1;
+                if depth == 0 {
                     return false;
                 }
             }
-            return true;
-        });
-        taken.cloned().collect()
+            Token::Operator(Operator::LogicalAnd) | Token::Operator(Operator::LogicalOr) if depth == 1 => return true,
+            Token::Operator(o) if depth == 1 && RELATIONS.contains(o) => return true,
+            _ => {}
+        }
+    }
+    // unclosed, let the condition parser point out the missing `)`
+    true
+}
+
+// where the parentheses starting `tks` are closed, if they start with one and are
+fn group_end(tks: &[Spanned<Token>]) -> Option<usize> {
+    if tks.first().map(|t| &t.node) != Some(&Token::Separator(Separator::OpenParentheses)) {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, token) in tks.iter().enumerate() {
+        match &token.node {
+            Token::Separator(Separator::OpenParentheses) => depth += 1,
+            Token::Separator(Separator::CloseParentheses) => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// the empty else slot at the end of an if / else if chain, if there is one
+fn open_else(scoped: &mut ScopeImpl) -> Option<&mut Option<Box<ScopeImpl>>> {
+    match &mut scoped.scope_type {
+        ScopeImplType::If { else_scope, .. } => {
+            if else_scope.is_none() {
+                return Some(else_scope);
+            }
+            open_else(else_scope.as_mut().unwrap())
+        }
+        _ => None,
+    }
+}
+
+fn unexpected(found: &Spanned<Token>, expected: &str) -> Diagnostic {
+    Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, format!("expected {}, found {}", expected, describe(&found.node)))
+        .with_primary(found.span, format!("expected {}", expected))
+}
+
+// how a token is named in diagnostics
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Eof => "end of file".to_string(),
+        Token::Newline => "newline".to_string(),
+        Token::Inline(_) => "inline assembly".to_string(),
+        Token::Literal(Literal::String(_)) => "string literal".to_string(),
+        Token::Literal(_) => "literal".to_string(),
+        Token::Identifier(Identifier::Variable(v)) => format!("`{}`", String::from_utf8_lossy(v)),
+        Token::Identifier(Identifier::Register(r)) => format!("`${}`", String::from_utf8_lossy(r)),
+        Token::Identifier(Identifier::DerefRegister(r)) => format!("`$*{}`", String::from_utf8_lossy(r)),
+        Token::Keyword(kw) => format!("keyword `{}`", kw),
+        Token::Operator(op) => format!("`{}`", op),
+        Token::Separator(sep) => format!("`{}`", sep),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::IStream;
+    use crate::tokenizer::Tokenizer;
+
+    // parses `source` as an expression and prints the tree in prefix form
+    fn parse(source: &str) -> Result<String, Diagnostic> {
+        let text = source.as_bytes().to_vec();
+        let mut is = IStream::new(&text, 0);
+        let tokenizer = Tokenizer::new(&mut is);
+        let tokens = tokenizer.get_tokens();
+        let parser = Parser::new(&tokens[tokens.len() - 1..]).unwrap();
+        parser.parse_expression(&tokens[..tokens.len() - 1], Span::default()).map(|e| tree(&e))
+    }
+
+    fn tree(expression: &Expression) -> String {
+        match expression {
+            Expression::Binary { left, right, operator, .. } => format!("({} {} {})", operator, tree(left), tree(right)),
+            Expression::Unary { operand, operator, .. } => format!("({} {})", operator, tree(operand)),
+            Expression::Load { address, .. } => {
+                let base = address.symbol.as_ref().or(address.base.as_ref()).map_or("_".to_string(), |b| String::from_utf8_lossy(b).to_string());
+                let index = address.index.as_ref().map_or("_".to_string(), |(i, scale)| format!("{}*{}", String::from_utf8_lossy(i), scale));
+                format!("[{} {} {}]", base, index, address.displacement)
+            }
+            Expression::Number { value, .. } => value.to_string(),
+            Expression::Register { reg, .. } => format!("${}", String::from_utf8_lossy(reg)),
+            Expression::Variable { var, .. } => String::from_utf8_lossy(var).to_string(),
+        }
+    }
+
+    #[test]
+    fn same_precedence_is_left_associative() {
+        assert_eq!(parse("$rax - $rsi - 1").unwrap(), "(- (- $rax $rsi) 1)");
+        assert_eq!(parse("8 / 4 / 2").unwrap(), "(/ (/ 8 4) 2)");
+        assert_eq!(parse("$rax + 1 - 2 + 3").unwrap(), "(+ (- (+ $rax 1) 2) 3)");
+    }
+
+    #[test]
+    fn multiplication_binds_tighter() {
+        assert_eq!(parse("$rax + $rsi * 4 * 2 + $rdi").unwrap(), "(+ (+ $rax (* (* $rsi 4) 2)) $rdi)");
+        assert_eq!(parse("1 - 6 % 4 / 2").unwrap(), "(- 1 (/ (% 6 4) 2))");
+    }
+
+    #[test]
+    fn bitwise_binds_looser_than_arithmetic() {
+        assert_eq!(parse("$rax | $rbx & 1 << 2 + 1").unwrap(), "(| $rax (& $rbx (<< 1 (+ 2 1))))");
+        assert_eq!(parse("$rax ^ $rbx | ~$rcx & 255").unwrap(), "(| (^ $rax $rbx) (& (~ $rcx) 255))");
+        assert_eq!(parse("$rax >>s 3 >> 1").unwrap(), "(>> (>>s $rax 3) 1)");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn negation_is_unary() {
+        assert_eq!(parse("-$rax * 2").unwrap(), "(* (- $rax) 2)");
+        assert_eq!(parse("$rax - -0x10").unwrap(), "(- $rax (- 16))");
+        assert_eq!(parse("'A' + 0b1_0").unwrap(), "(+ 65 2)");
+        assert_eq!(parse("'\\'' + '\\n'").unwrap(), "(+ 39 10)");
+        assert_eq!(parse("-0x8000000000000000").unwrap(), "(- 9223372036854775808)");
+        assert!(parse("-18446744073709551615").is_err());
+        assert!(parse("$rax - -0x8000000000000001").is_err());
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(parse("($rax + 1) * 2").unwrap(), "(* (+ $rax 1) 2)");
+        assert_eq!(parse("$rax - ($rsi - 1)").unwrap(), "(- $rax (- $rsi 1))");
+        assert_eq!(parse("((sizeof(buf)))").unwrap(), "BUF_LEN");
+    }
+
+    #[test]
+    fn loads_take_an_address() {
+        assert_eq!(parse("$*rsi + 1").unwrap(), "(+ [rsi _ 0] 1)");
+        assert_eq!(parse("$*(rsi + rcx*8 + 16)").unwrap(), "[rsi rcx*8 16]");
+        assert_eq!(parse("$*(8*$rcx - 4 + rdi)").unwrap(), "[rdi rcx*8 -4]");
+        assert_eq!(parse("$*(rsi + rcx)").unwrap(), "[rsi rcx*1 0]");
+        assert_eq!(parse("file_data[$rcx - 1] + 1").unwrap(), "(+ [file_data rcx*1 -1] 1)");
+        assert!(parse("$*(rsi - rcx)").is_err());
+        assert!(parse("file_data[$ecx]").is_err());
+        assert!(parse("$*(rsi + rcx*3)").is_err());
+        assert!(parse("$*(rsi + rcx + rdx)").is_err());
+    }
+
+    // parses `source` as a whole program
+    fn parses(source: &str) -> bool {
+        let text = source.as_bytes().to_vec();
+        let mut is = IStream::new(&text, 0);
+        let tokenizer = Tokenizer::new(&mut is);
+        Parser::new(tokenizer.get_tokens()).is_ok()
+    }
+
+    #[test]
+    fn nested_condition_groups() {
+        assert!(parses("fn f {\nif (($rax == 1)) {\n}\n}"));
+        assert!(parses("fn f {\nif !(($rax == 0 || $rdi == 3)) {\n}\n}"));
+        assert!(parses("fn f {\nif ((($rax))) == 1 && ((($rdi == 2))) {\n}\n}"));
+        assert!(!parses("fn f {\nif (($rax == 1) {\n}\n}"));
+    }
+
+    #[test]
+    fn variables_cant_shadow_parameters() {
+        assert!(!parses("fn g(x: reg) {\nlet x = 3\n$rax = x\n}"));
+        assert!(!parses("fn g(x: reg) {\nif $rax == 0 {\nconst x = 3\n}\n}"));
+        assert!(parses("fn g(x: reg) {\nlet y = 3\n$rax = x\n}\nfn h {\nlet x = 3\n}"));
+    }
+
+    #[test]
+    fn malformed_expressions_are_errors() {
+        assert!(parse("($rax + 1").is_err());
+        assert!(parse("$rax +").is_err());
+        assert!(parse("$rax 1").is_err());
+        assert!(parse("()").is_err());
+    }
+}
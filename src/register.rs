@@ -0,0 +1,63 @@
+use crate::tokenizer::Type;
+
+// every general purpose register by its qword, dword, word and byte names
+const REGISTERS: [[&str; 4]; 16] = [
+    ["rax", "eax", "ax", "al"],
+    ["rbx", "ebx", "bx", "bl"],
+    ["rcx", "ecx", "cx", "cl"],
+    ["rdx", "edx", "dx", "dl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+    ["rbp", "ebp", "bp", "bpl"],
+    ["rsp", "esp", "sp", "spl"],
+    ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"],
+    ["r10", "r10d", "r10w", "r10b"],
+    ["r11", "r11d", "r11w", "r11b"],
+    ["r12", "r12d", "r12w", "r12b"],
+    ["r13", "r13d", "r13w", "r13b"],
+    ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+
+// where the System V ABI passes the first six integer arguments, in order
+pub const ARGUMENTS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+// registers a call is free to overwrite, anything that isn't callee saved besides rsp
+pub const CALLER_SAVED: [&str; 9] = ["rax", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11"];
+
+// registers a function has to give back the way it found them
+pub const CALLEE_SAVED: [&str; 6] = ["rbx", "rbp", "r12", "r13", "r14", "r15"];
+
+const WIDTHS: [Type; 4] = [Type::Uint64, Type::Uint32, Type::Uint16, Type::Uint8];
+
+// the row of `name` in REGISTERS, along with how wide it is
+fn find(name: &[u8]) -> Option<(&'static [&'static str; 4], Type)> {
+    REGISTERS.iter().find_map(|names| {
+        names
+            .iter()
+            .position(|n| n.as_bytes() == name)
+            .map(|column| (names, WIDTHS[column]))
+    })
+}
+
+pub fn is_register(name: &[u8]) -> bool {
+    find(name).is_some()
+}
+
+// everything past the tokenizer has been checked with is_register, so these can't fail
+
+// the qword register `name` is a part of, `r8d` is in `r8`
+pub fn family(name: &[u8]) -> &'static str {
+    find(name).expect("registers are validated when they're parsed").0[0]
+}
+
+pub fn width(name: &[u8]) -> Type {
+    find(name).expect("registers are validated when they're parsed").1
+}
+
+// the part of a register that's `width` wide, `sized(b"rcx", Type::Uint8)` is `cl`
+pub fn sized(name: &[u8], width: Type) -> &'static str {
+    let column = WIDTHS.iter().position(|w| *w == width).unwrap();
+    find(name).expect("registers are validated when they're parsed").0[column]
+}
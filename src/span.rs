@@ -0,0 +1,78 @@
+use std::ops::Range;
+
+// a byte range inside one of the files held by a SourceMap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: usize, start: usize, end: usize) -> Span {
+        Span { file, start, end }
+    }
+
+    // from the start of this span to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.file, self.start, other.end)
+    }
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub text: Vec<u8>,
+    // byte offset of the start of every line, always starts with 0
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    // zero based (line, column) of a byte offset
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        (line, offset - self.line_starts[line])
+    }
+
+    // byte range of a line without its newline
+    pub fn line_range(&self, line: usize) -> Range<usize> {
+        let start = self.line_starts[line];
+        let end = self.text[start..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(self.text.len(), |end| start + end);
+        start..end
+    }
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: vec![] }
+    }
+
+    // returns the file id used by spans pointing into this file
+    pub fn add(&mut self, name: String, text: Vec<u8>) -> usize {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.iter().enumerate().filter(|(_, &c)| c == b'\n').map(|(i, _)| i + 1));
+        self.files.push(SourceFile { name, text, line_starts });
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, file: usize) -> &SourceFile {
+        &self.files[file]
+    }
+}
+
+// a value along with where it came from in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
@@ -1,10 +1,9 @@
+use crate::span::Span;
+
 pub struct IStream<'a> {
-    file_name: &'a String,
-    input: &'a Vec<u8>,
-    pub row: usize,
-    pub col: usize,
+    file: usize,
+    input: &'a [u8],
     idx: usize,
-    line_idx: usize,
     pub empty: bool,
 }
 
@@ -12,13 +11,6 @@ impl IStream<'_> {
     pub fn next(&mut self) -> u8 {
         let nchar: u8 = self.input[self.idx];
         self.idx += 1;
-        if nchar == b'\n' {
-            self.row += 1;
-            self.col = 0;
-            self.line_idx = self.idx;
-        } else {
-            self.col += 1;
-        }
         nchar
     }
 
@@ -35,14 +27,17 @@ impl IStream<'_> {
         }
     }
 
-    pub fn get_current_line(&self) -> &str {
-        let line = &self.input[self.line_idx..];
-        let line_end = line.iter().position(|&c| c == b'\n').unwrap();
-        std::str::from_utf8(&line[..line_end]).unwrap()
+    // byte offset of the next character
+    pub fn pos(&self) -> usize {
+        self.idx
+    }
+
+    pub fn span_from(&self, start: usize) -> Span {
+        Span::new(self.file, start, self.idx)
     }
 
     pub fn take_to_c(&mut self, c: u8) {
-        while self.next() != c {}
+        while self.peek(0).is_some() && self.next() != c {}
     }
 
     pub fn take_while(&mut self, f: impl Fn(u8, Option<&mut IStream>) -> bool) -> Vec<u8> {
@@ -63,29 +58,15 @@ impl IStream<'_> {
                 }
             }
         }
-        return chars;
+        chars
     }
 
-    pub fn err(&self) {
-        println!("Could not parse: {}", self.file_name);
-        let row_s = format!("{}", self.row);
-        println!("{}: {}", row_s, self.get_current_line());
-        for _ in 0..self.col + row_s.len() + 2 {
-            print!(" ")
-        }
-        print!("^");
-        println!()
-    }
-
-    pub fn new<'a>(input: &'a Vec<u8>, file_name: &'a String) -> IStream<'a> {
+    pub fn new(input: &[u8], file: usize) -> IStream<'_> {
         IStream {
-            file_name,
+            file,
             input,
-            row: 0,
-            col: 0,
             idx: 0,
-            line_idx: 0,
             empty: false,
         }
     }
-}
\ No newline at end of file
+}
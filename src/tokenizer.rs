@@ -1,136 +1,308 @@
+use std::fmt;
 use std::str;
 
 use crate::stream::{
     IStream,
 };
 use crate::combinator;
+use crate::register;
+use crate::diagnostic::{self, Diagnostic};
+use crate::span::{Span, Spanned};
 
 
 pub struct Tokenizer<'a> {
     istream: &'a mut IStream<'a>,
-    tokens: Vec<Token>,
+    tokens: Vec<Spanned<Token>>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Tokenizer<'_> {
+    // tokens that can't be read are reported and left out, so the rest can still be parsed
     pub fn new<'a>(istream: &'a mut IStream<'a>) -> Tokenizer<'a> {
-        let tokens = Vec::<Token>::new();
+        let tokens = Vec::<Spanned<Token>>::new();
         let mut tokenizer = Tokenizer {
             istream,
             tokens,
+            diagnostics: vec![],
         };
         tokenizer.find_tokens();
         tokenizer
     }
 
-    pub fn get_tokens(&self) -> &Vec<Token> {
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    pub fn get_tokens(&self) -> &[Spanned<Token>] {
         &self.tokens
     }
 
     fn find_tokens(&mut self) {
         loop {
-            let token: Token = self.take_token();
-            match token {
-                Token::Eof => break,
-                _ => {}
-            }
+            // bad input has already been consumed, so just report it and keep going
+            let token = match self.take_token() {
+                Ok(token) => token,
+                Err(d) => {
+                    self.diagnostics.push(d);
+                    continue;
+                }
+            };
+            let eof = token.node == Token::Eof;
             self.tokens.push(token);
+            if eof {
+                break;
+            }
+        }
+    }
+
+    // `255`, `0xff`, `0o377` or `0b1111_1111`
+    fn take_int(&mut self, start: usize) -> Result<usize, Diagnostic> {
+        // take anything that could be a digit, so `0xfg` is one bad number instead of a number and a name
+        let num = self.istream.take_while(|c, _| c.is_ascii_alphanumeric() || c == b'_');
+        let num_str = str::from_utf8(&num).unwrap();
+        let (radix, digits) = match num_str.get(..2) {
+            Some("0x") => (16, &num_str[2..]),
+            Some("0o") => (8, &num_str[2..]),
+            Some("0b") => (2, &num_str[2..]),
+            _ => (10, num_str),
+        };
+        let digits: String = digits.chars().filter(|&c| c != '_').collect();
+        let span = self.istream.span_from(start);
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(Diagnostic::error(diagnostic::INVALID_NUMBER, format!("invalid integer literal `{}`", num_str))
+                .with_primary(span, format!("not a base {} number", radix))
+                .with_note("numbers are written like `255`, `0xff`, `0o377`, `0b1111_1111` or `'A'`"));
+        }
+        match u64::from_str_radix(&digits, radix) {
+            Ok(n) => Ok(n as usize),
+            Err(_) => Err(Diagnostic::error(diagnostic::INVALID_NUMBER, format!("integer literal `{}` is too large", num_str))
+                .with_primary(span, "does not fit in 64 bits")),
         }
-        self.tokens.push(Token::Eof);
     }
 
-    fn take_token(&mut self) -> Token {
-        // clear ws
-        self.istream.take_while(&combinator::is_ws);
+    // `'A'` is the number of the byte, and can be any escape a string can have
+    fn take_char(&mut self, start: usize) -> Result<usize, Diagnostic> {
+        // take open quote
+        self.istream.next();
+        let c = match self.istream.peek(0) {
+            Some(b'\\') => {
+                self.istream.next();
+                self.take_escape()
+            }
+            Some(c) if c != b'\'' && c != b'\n' => {
+                self.istream.next();
+                Some(c)
+            }
+            _ => None,
+        };
+        match (c, self.istream.peek(0)) {
+            (Some(c), Some(b'\'')) => {
+                self.istream.next();
+                Ok(c as usize)
+            }
+            _ => {
+                // skip to the closing quote so the rest of the line still tokenizes
+                self.istream.take_while(|c, _| c != b'\'' && c != b'\n');
+                if self.istream.peek(0) == Some(b'\'') {
+                    self.istream.next();
+                }
+                Err(Diagnostic::error(diagnostic::INVALID_CHARACTER, "invalid character literal")
+                    .with_primary(self.istream.span_from(start), "expected a single byte in quotes")
+                    .with_note("characters look like `'A'` or `'\\n'`"))
+            }
+        }
+    }
+
+    // the bytes a string literal stands for, with its escapes decoded
+    fn take_string(&mut self, start: usize) -> Result<Vec<u8>, Diagnostic> {
+        // take open quote
+        self.istream.next();
+        let mut ss = vec![];
+        // a bad escape is reported once the whole string is consumed, so the rest of it isn't tokenized
+        let mut bad_escape = None;
+        loop {
+            match self.istream.peek(0) {
+                None | Some(b'\n') => {
+                    return Err(Diagnostic::error(diagnostic::UNTERMINATED_STRING, "unterminated string literal")
+                        .with_primary(self.istream.span_from(start), "string starts here"));
+                }
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let escape = self.istream.pos();
+                    self.istream.next();
+                    match self.take_escape() {
+                        Some(c) => ss.push(c),
+                        None => {
+                            bad_escape.get_or_insert(self.istream.span_from(escape));
+                        }
+                    }
+                }
+                Some(c) => {
+                    ss.push(c);
+                    self.istream.next();
+                }
+            }
+        }
+        // take closing quote
+        self.istream.next();
+        match bad_escape {
+            Some(span) => Err(Diagnostic::error(diagnostic::INVALID_ESCAPE, "unknown escape sequence")
+                .with_primary(span, "not a valid escape")
+                .with_note("valid escapes are `\\n`, `\\t`, `\\0`, `\\xNN`, `\\\\`, `\\\"` and `\\'`")),
+            None => Ok(ss),
+        }
+    }
+
+    // the byte after a `\`, or None if it isn't a valid escape
+    fn take_escape(&mut self) -> Option<u8> {
+        let c = self.istream.peek(0).filter(|&c| c != b'\n')?;
+        self.istream.next();
+        match c {
+            b'n' => Some(b'\n'),
+            b't' => Some(b'\t'),
+            b'0' => Some(0),
+            b'\\' | b'"' | b'\'' => Some(c),
+            b'x' => {
+                let digits: Vec<u8> = (0..2).map_while(|i| self.istream.peek(i).filter(u8::is_ascii_hexdigit)).collect();
+                for _ in &digits {
+                    self.istream.next();
+                }
+                if digits.len() == 2 {
+                    u8::from_str_radix(str::from_utf8(&digits).unwrap(), 16).ok()
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // wraps take_token_kind with the span of everything it consumed
+    fn take_token(&mut self) -> Result<Spanned<Token>, Diagnostic> {
+        // clear ws, and if comment, we should read until the end of the line
+        self.istream.take_while(combinator::is_ws);
+        if self.istream.peek(0) == Some(b';') {
+            self.istream.take_while(|c, _| c != b'\n');
+        }
+        let start = self.istream.pos();
+        let token = self.take_token_kind()?;
+        Ok(Spanned { node: token, span: self.istream.span_from(start) })
+    }
+
+    fn take_token_kind(&mut self) -> Result<Token, Diagnostic> {
         if self.istream.empty {
-            return Token::Eof;
+            return Ok(Token::Eof);
         }
+        let start = self.istream.pos();
         let peek: u8 = self.istream.peek(0).unwrap();
         if peek == b'\n' {
             self.istream.take_to_c(b'\n');
-            return Token::Newline;
-        }
-        // if comment, we should read until the end of the line
-        if peek == b';' {
-            self.istream.take_to_c(b'\n');
-            return self.take_token();
+            return Ok(Token::Newline);
         }
         if peek == b'#' {
             self.istream.next();
             let inline_asm = self.istream.take_while(|c, _| c != b'\n');
-            return Token::Inline(inline_asm);
+            return Ok(Token::Inline(inline_asm));
         }
-        if combinator::is_quote(peek, None) {
-            // take open quote
-            self.istream.next();
-            let ss = self.istream.take_while(&|c: u8, tk: Option<&mut IStream>| -> bool {
-                // we should keep taking if !quote or it can be a quote but the prev char is \
-                !combinator::is_quote(c, None) || tk.unwrap().peek(-1).unwrap() == b'\\'
-            });
-            // take closing quote
+        // `c"..."` strings end with a NUL, for anything expecting a C string
+        if peek == b'c' && self.istream.peek(1).is_some_and(|c| combinator::is_quote(c, None)) {
             self.istream.next();
-            Token::Literal(Literal::String(ss))
+            let mut ss = self.take_string(start)?;
+            ss.push(0);
+            Ok(Token::Literal(Literal::String(ss)))
+        } else if combinator::is_quote(peek, None) {
+            Ok(Token::Literal(Literal::String(self.take_string(start)?)))
         } else if combinator::is_int(peek, None) {
-            let num = self.istream.take_while(&combinator::is_int);
-            let num_str: &str = str::from_utf8(&num).unwrap();
-            Token::Literal(Literal::Int(num_str.parse::<usize>().unwrap()))
+            Ok(Token::Literal(Literal::Int(self.take_int(start)?)))
+        } else if peek == b'\'' {
+            Ok(Token::Literal(Literal::Int(self.take_char(start)?)))
         } else if combinator::is_kw_or_var(peek, None) {
-            let kw_or_var = self.istream.take_while(&combinator::is_kw_or_var);
-            match get_kw(&kw_or_var) {
+            // names can have digits after the first character, like `r10`
+            let kw_or_var = self.istream.take_while(|c, is| combinator::is_kw_or_var(c, is) || combinator::is_int(c, None));
+            Ok(match get_kw(&kw_or_var) {
                 Some(kw) => Token::Keyword(kw),
                 None => Token::Identifier(Identifier::Variable(kw_or_var))
-            }
+            })
         } else if combinator::is_op(peek, None) {
-            let op = self.istream.take_while(combinator::is_op);
-            match get_op(&op) {
-                Some(op) => Token::Operator(op),
+            // operators can run into each other (`$rax=-1`), so take the longest one that's valid
+            let run: Vec<u8> = (0..3).map_while(|i| self.istream.peek(i).filter(|&c| combinator::is_op(c, None))).collect();
+            let (len, mut op) = match (1..=run.len()).rev().find_map(|len| get_op(&run[..len]).map(|op| (len, op))) {
+                Some(found) => found,
                 None => {
-                    self.istream.err();
-                    Token::Eof
+                    self.istream.next();
+                    return Err(Diagnostic::error(diagnostic::UNKNOWN_OPERATOR, format!("unknown operator `{}`", run[0] as char))
+                        .with_primary(self.istream.span_from(start), "not a valid operator"));
+                }
+            };
+            for _ in 0..len {
+                self.istream.next();
+            }
+            // relations, division, modulus and `>>` can be marked signed or unsigned with a trailing s or u, like `<s`
+            let suffix = self.istream.peek(0);
+            let after = self.istream.peek(1).unwrap_or(b' ');
+            if matches!(suffix, Some(b's') | Some(b'u')) && !combinator::is_kw_or_var(after, None) && !combinator::is_int(after, None) {
+                if let Some(signed) = get_signed_op(&op, suffix == Some(b's')) {
+                    self.istream.next();
+                    op = signed;
                 }
             }
+            Ok(Token::Operator(op))
         } else if peek == b'$' {
             self.istream.next();
-            if self.istream.peek(0).unwrap() == b'*' {
+            let deref = self.istream.peek(0) == Some(b'*');
+            if deref {
                 self.istream.next();
-                let reg = self.istream.take_while(combinator::is_register_name);
-                Token::Identifier(Identifier::DerefRegister(reg))
+                // `$*(rsi + 8)` dereferences a whole address, which the parser takes from here
+                if self.istream.peek(0) == Some(b'(') {
+                    return Ok(Token::Operator(Operator::Deref));
+                }
+            }
+            let reg = self.istream.take_while(combinator::is_register_name);
+            if reg.is_empty() {
+                return Err(Diagnostic::error(diagnostic::MISSING_REGISTER, "expected a register name after `$`")
+                    .with_primary(self.istream.span_from(start), "missing register name"));
+            }
+            if !register::is_register(&reg) {
+                return Err(unknown_register(&reg, self.istream.span_from(start)));
+            }
+            if deref {
+                Ok(Token::Identifier(Identifier::DerefRegister(reg)))
             } else {
-                let reg = self.istream.take_while(combinator::is_register_name);
-                Token::Identifier(Identifier::Register(reg))
+                Ok(Token::Identifier(Identifier::Register(reg)))
             }
         } else {
-            let sep = get_sep(peek);
-            match sep {
-                Some(separator) => {
-                    self.istream.next();
-                    Token::Separator(separator)
-                }
-                None => {
-                    self.istream.err();
-                    Token::Eof
-                }
+            self.istream.next();
+            match get_sep(peek) {
+                Some(separator) => Ok(Token::Separator(separator)),
+                None => Err(Diagnostic::error(diagnostic::UNEXPECTED_CHARACTER, format!("unexpected character `{}`", peek.escape_ascii()))
+                    .with_primary(self.istream.span_from(start), "not valid here")),
             }
         }
     }
 }
 
 
-pub fn get_kw(s: &Vec<u8>) -> Option<Keyword> {
-    // &Vec -> Vec -> [u8] -> &[u8] for cmp
-    match &**s {
+pub fn get_kw(s: &[u8]) -> Option<Keyword> {
+    match s {
         b"const" => Some(Keyword::Const),
         b"let" => Some(Keyword::Let),
         b"fn" => Some(Keyword::Function),
         b"extern" => Some(Keyword::External),
         b"if" => Some(Keyword::If),
+        b"else" => Some(Keyword::Else),
+        b"while" => Some(Keyword::While),
+        b"loop" => Some(Keyword::Loop),
+        b"break" => Some(Keyword::Break),
+        b"continue" => Some(Keyword::Continue),
         b"call" => Some(Keyword::Call),
+        b"return" => Some(Keyword::Return),
         b"sizeof" => Some(Keyword::SizeOf),
         _ => None
     }
 }
 
-pub fn get_op(s: &Vec<u8>) -> Option<Operator> {
-    match &**s {
+pub fn get_op(s: &[u8]) -> Option<Operator> {
+    match s {
         b"=" => Some(Operator::Assign),
         b"+" => Some(Operator::Add),
         b"-" => Some(Operator::Subtract),
@@ -141,7 +313,40 @@ pub fn get_op(s: &Vec<u8>) -> Option<Operator> {
         b"++" => Some(Operator::Increment),
         b"!" => Some(Operator::Not),
         b"!=" => Some(Operator::NotEqual),
+        b"&&" => Some(Operator::LogicalAnd),
+        b"||" => Some(Operator::LogicalOr),
         b"==" => Some(Operator::Equal),
+        b"<" => Some(Operator::Less),
+        b"<=" => Some(Operator::LessEqual),
+        b">" => Some(Operator::Greater),
+        b">=" => Some(Operator::GreaterEqual),
+        b"&" => Some(Operator::BitAnd),
+        b"|" => Some(Operator::BitOr),
+        b"^" => Some(Operator::BitXor),
+        b"~" => Some(Operator::BitNot),
+        b"<<" => Some(Operator::ShiftLeft),
+        b">>" => Some(Operator::ShiftRight),
+        _ => None,
+    }
+}
+
+// the signed or unsigned version of an operator that has both, plain ones are unsigned
+pub fn get_signed_op(op: &Operator, signed: bool) -> Option<Operator> {
+    match (op, signed) {
+        (Operator::Divide, false) => Some(Operator::Divide),
+        (Operator::Modulus, false) => Some(Operator::Modulus),
+        (Operator::Divide, true) => Some(Operator::SignedDivide),
+        (Operator::Modulus, true) => Some(Operator::SignedModulus),
+        (Operator::ShiftRight, false) => Some(Operator::ShiftRight),
+        (Operator::ShiftRight, true) => Some(Operator::SignedShiftRight),
+        (Operator::Less, false) => Some(Operator::Less),
+        (Operator::LessEqual, false) => Some(Operator::LessEqual),
+        (Operator::Greater, false) => Some(Operator::Greater),
+        (Operator::GreaterEqual, false) => Some(Operator::GreaterEqual),
+        (Operator::Less, true) => Some(Operator::SignedLess),
+        (Operator::LessEqual, true) => Some(Operator::SignedLessEqual),
+        (Operator::Greater, true) => Some(Operator::SignedGreater),
+        (Operator::GreaterEqual, true) => Some(Operator::SignedGreaterEqual),
         _ => None,
     }
 }
@@ -160,9 +365,11 @@ pub fn get_sep(c: u8) -> Option<Separator> {
     }
 }
 
-pub fn get_type(t: &Vec<u8>) -> Option<Type> {
-    match &**t {
+pub fn get_type(t: &[u8]) -> Option<Type> {
+    match t {
         b"byte" => Some(Type::Uint8),
+        b"word" => Some(Type::Uint16),
+        b"dword" => Some(Type::Uint32),
         b"qword" => Some(Type::Uint64),
         _ => None,
     }
@@ -184,6 +391,7 @@ pub enum Token {
 pub enum Literal {
     String(Vec<u8>),
     Int(usize),
+    // the values, their type, and how many elements there are including padding
     Array(Vec<Literal>, Type, usize),
 }
 
@@ -199,14 +407,39 @@ pub enum Operator {
     Assign,
     Add,
     Subtract,
+    // unsigned
     Divide,
     Multiply,
+    // unsigned
     Modulus,
+    SignedDivide,
+    SignedModulus,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    // logical, shifts in zeros
+    ShiftRight,
+    // arithmetic, shifts in the sign bit
+    SignedShiftRight,
+    Deref,
     Decrement,
     Increment,
     Not,
     Equal,
     NotEqual,
+    LogicalAnd,
+    LogicalOr,
+    // unsigned
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    SignedLess,
+    SignedLessEqual,
+    SignedGreater,
+    SignedGreaterEqual,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -229,42 +462,232 @@ pub enum Keyword {
     Function,
     External,
     If,
+    Else,
+    While,
+    Loop,
+    Break,
+    Continue,
     Call,
+    Return,
     SizeOf,
 }
-#[derive(Debug, Clone, PartialEq)]
 
+// ordered from narrowest to widest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Type {
     Uint8,
+    Uint16,
+    Uint32,
     Uint64,
 }
 
-pub fn get_v_description(vt: &Vec<Token>) -> (Type, usize) {
-    assert_eq!(vt.len(), 6);
-    assert_eq!(vt[0], Token::Separator(Separator::Colon));
-    assert_eq!(vt[1], Token::Separator(Separator::OpenBracket));
-    let v_type = match &vt[2] {
-        Token::Identifier(Identifier::Variable(t)) => get_type(t).unwrap(),
-        _ => panic!("invalid type"),
+impl Type {
+    // in bytes
+    pub fn size(self) -> usize {
+        match self {
+            Type::Uint8 => 1,
+            Type::Uint16 => 2,
+            Type::Uint32 => 4,
+            Type::Uint64 => 8,
+        }
+    }
+
+    // values can be given unsigned or signed, `255` and `-1` both fit in a byte
+    pub fn fits(self, value: usize) -> bool {
+        let bits = self.size() * 8;
+        let signed = value as i64;
+        bits == 64 || value < 1 << bits || (signed < 0 && signed >= -(1 << (bits - 1)))
+    }
+}
+
+// the inverse of get_kw, get_op and get_sep, used when printing tokens back out
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Keyword::Const => "const",
+            Keyword::Let => "let",
+            Keyword::Function => "fn",
+            Keyword::External => "extern",
+            Keyword::If => "if",
+            Keyword::Else => "else",
+            Keyword::While => "while",
+            Keyword::Loop => "loop",
+            Keyword::Break => "break",
+            Keyword::Continue => "continue",
+            Keyword::Call => "call",
+            Keyword::Return => "return",
+            Keyword::SizeOf => "sizeof",
+        })
+    }
+}
+
+// the names are also what NASM calls each width
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Type::Uint8 => "byte",
+            Type::Uint16 => "word",
+            Type::Uint32 => "dword",
+            Type::Uint64 => "qword",
+        })
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Operator::Assign => "=",
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Divide => "/",
+            Operator::Multiply => "*",
+            Operator::Modulus => "%",
+            Operator::Decrement => "--",
+            Operator::Increment => "++",
+            Operator::Not => "!",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::LogicalAnd => "&&",
+            Operator::LogicalOr => "||",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+            Operator::SignedLess => "<s",
+            Operator::SignedLessEqual => "<=s",
+            Operator::SignedGreater => ">s",
+            Operator::SignedGreaterEqual => ">=s",
+            Operator::SignedDivide => "/s",
+            Operator::SignedModulus => "%s",
+            Operator::BitAnd => "&",
+            Operator::BitOr => "|",
+            Operator::BitXor => "^",
+            Operator::BitNot => "~",
+            Operator::ShiftLeft => "<<",
+            Operator::ShiftRight => ">>",
+            Operator::SignedShiftRight => ">>s",
+            Operator::Deref => "$*",
+        })
+    }
+}
+
+impl fmt::Display for Separator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Separator::OpenBracket => "[",
+            Separator::CloseBracket => "]",
+            Separator::OpenBrace => "{",
+            Separator::CloseBrace => "}",
+            Separator::Colon => ":",
+            Separator::OpenParentheses => "(",
+            Separator::CloseParentheses => ")",
+            Separator::Comma => ",",
+        })
+    }
+}
+
+pub fn get_v_description(vt: &[Spanned<Token>]) -> Result<(Type, usize), Diagnostic> {
+    let span = Span::new(vt[0].span.file, vt[0].span.start, vt[vt.len() - 1].span.end);
+    let bad_description = || Diagnostic::error(diagnostic::INVALID_DESCRIPTION, "invalid variable description")
+        .with_primary(span, "expected `: [type, count]`")
+        .with_note("descriptions look like `: [byte, 32]`");
+    let nodes: Vec<&Token> = vt.iter().map(|t| &t.node).collect();
+    if nodes.len() != 6
+        || *nodes[0] != Token::Separator(Separator::Colon)
+        || *nodes[1] != Token::Separator(Separator::OpenBracket)
+        || *nodes[3] != Token::Separator(Separator::Comma)
+        || *nodes[5] != Token::Separator(Separator::CloseBracket) {
+        return Err(bad_description());
+    }
+    let v_type = match nodes[2] {
+        Token::Identifier(Identifier::Variable(t)) => get_type(t).ok_or_else(|| unknown_type(t, vt[2].span))?,
+        _ => return Err(bad_description()),
     };
-    assert_eq!(vt[3], Token::Separator(Separator::Comma));
-    let v_size = match &vt[4] {
-        Token::Literal(Literal::Int(i)) => i,
-        _ => panic!("invalid size"),
+    let v_size = match nodes[4] {
+        Token::Literal(Literal::Int(i)) => *i,
+        _ => return Err(bad_description()),
     };
-    assert_eq!(vt[5], Token::Separator(Separator::CloseBracket));
-    (v_type, *v_size)
+    Ok((v_type, v_size))
+}
+
+// `-value` as two's complement, as long as it fits in 64 bits signed
+pub fn negate(value: usize, span: Span) -> Result<usize, Diagnostic> {
+    if value as u64 > 1 << 63 {
+        return Err(Diagnostic::error(diagnostic::INVALID_NUMBER, format!("integer literal `-{}` is too small", value))
+            .with_primary(span, "does not fit in 64 bits"));
+    }
+    Ok(value.wrapping_neg())
+}
+
+// `[1, 2, 3]`
+fn get_array(vt: &[Spanned<Token>], description: Option<(Type, usize)>) -> Result<Literal, Diagnostic> {
+    let span = Span::new(vt[0].span.file, vt[0].span.start, vt[vt.len() - 1].span.end);
+    let v_type = description.map_or(Type::Uint64, |(t, _)| t);
+    let mut values = vec![];
+    let mut expect_value = true;
+    let mut negative: Option<Span> = None;
+    for (i, token) in vt.iter().enumerate().skip(1) {
+        match &token.node {
+            Token::Operator(Operator::Subtract) if expect_value && negative.is_none() => negative = Some(token.span),
+            Token::Literal(Literal::Int(value)) if expect_value => {
+                let (value, written, span) = match negative.take() {
+                    Some(minus) => (negate(*value, minus.to(token.span))?, format!("-{}", value), minus.to(token.span)),
+                    None => (*value, value.to_string(), token.span),
+                };
+                if !v_type.fits(value) {
+                    return Err(Diagnostic::error(diagnostic::INVALID_LITERAL, format!("`{}` doesn't fit in a {}", written, v_type))
+                        .with_primary(span, format!("needs more than {} bits", v_type.size() * 8)));
+                }
+                values.push(Literal::Int(value));
+                expect_value = false;
+            }
+            Token::Separator(Separator::Comma) if !expect_value => expect_value = true,
+            Token::Separator(Separator::CloseBracket) if i == vt.len() - 1 && negative.is_none() => {
+                let count = description.map_or(values.len(), |(_, count)| count);
+                if values.len() > count {
+                    return Err(Diagnostic::error(diagnostic::INVALID_LITERAL, format!("{} values given for an array of {}", values.len(), count))
+                        .with_primary(span, format!("expected at most {} values", count)));
+                }
+                return Ok(Literal::Array(values, v_type, count));
+            }
+            _ => {
+                return Err(Diagnostic::error(diagnostic::INVALID_LITERAL, "invalid array literal")
+                    .with_primary(token.span, if expect_value { "expected a number" } else { "expected `,` or `]`" })
+                    .with_note("arrays look like `[1, 2, 3]`"));
+            }
+        }
+    }
+    Err(Diagnostic::error(diagnostic::INVALID_LITERAL, "unclosed array literal")
+        .with_primary(span, "expected `]` at the end"))
 }
 
-pub fn get_literal(vt: &Vec<Token>) -> Literal {
-    assert_ne!(vt.len(), 0);
-    match vt.first().unwrap() {
-        Token::Literal(l) => l.clone(),
-        // add support for constant arrays later on
-        // _ => {
-        //     assert_eq!(vt[0], Token::Separator(Separator::OpenBracket));
-        //
-        // }
-        _ => panic!("literal not recognized"),
+pub fn unknown_register(r: &[u8], span: Span) -> Diagnostic {
+    Diagnostic::error(diagnostic::UNKNOWN_REGISTER, format!("unknown register `{}`", String::from_utf8_lossy(r)))
+        .with_primary(span, "not a register")
+        .with_note("registers are named like `rax`, `eax`, `ax` and `al`, or `r8`, `r8d`, `r8w` and `r8b`")
+}
+
+pub fn unknown_type(t: &[u8], span: Span) -> Diagnostic {
+    Diagnostic::error(diagnostic::INVALID_DESCRIPTION, format!("unknown type `{}`", String::from_utf8_lossy(t)))
+        .with_primary(span, "not a type")
+        .with_note("valid types are `byte`, `word`, `dword` and `qword`")
+}
+
+// `assign` is the `=` before the literal, used when the literal is missing.
+// arrays take their type and length from the description, or are qwords exactly as long as the literal
+pub fn get_literal(vt: &[Spanned<Token>], assign: Span, description: Option<(Type, usize)>) -> Result<Literal, Diagnostic> {
+    match vt {
+        [Spanned { node: Token::Literal(l), .. }] => Ok(l.clone()),
+        [Spanned { node: Token::Operator(Operator::Subtract), span }, Spanned { node: Token::Literal(Literal::Int(value)), span: end }] => {
+            Ok(Literal::Int(negate(*value, span.to(*end))?))
+        }
+        [Spanned { node: Token::Separator(Separator::OpenBracket), .. }, ..] => get_array(vt, description),
+        [] => Err(Diagnostic::error(diagnostic::INVALID_LITERAL, "expected a literal value")
+            .with_primary(Span::new(assign.file, assign.end, assign.end), "expected a literal after `=`")),
+        _ => {
+            let span = Span::new(vt[0].span.file, vt[0].span.start, vt[vt.len() - 1].span.end);
+            Err(Diagnostic::error(diagnostic::INVALID_LITERAL, "expected a literal value")
+                .with_primary(span, "not a single literal"))
+        }
     }
-}
\ No newline at end of file
+}